@@ -1,6 +1,14 @@
 use crate::{Error, Flags, Result};
 use randomx_sys::*;
-use std::ptr;
+use std::{ptr, sync::Arc};
+
+/// Runs first thing on each thread initializing the cache or dataset, with the thread's index.
+/// Memory usually ends up near whichever core first writes to it,
+/// so pinning the threads here keeps the cache or dataset on their NUMA node.
+pub type InitHook = Arc<dyn Fn(u64) + Send + Sync>;
+
+struct UnsafeSend<T>(T);
+unsafe impl<T> Send for UnsafeSend<T> {}
 
 /// Combines the RandomX cache and optionally dataset
 pub struct Cache {
@@ -11,6 +19,17 @@ pub struct Cache {
 
 impl Cache {
     pub fn new(flags: Flags, key: &[u8], init_threads: u64) -> Result<Self> {
+        Self::with_init_hook(flags, key, init_threads, None)
+    }
+
+    /// Like `new`, but runs `hook` on each thread initializing it, the cache too being initialized
+    /// on a thread of its own.
+    pub fn with_init_hook(
+        flags: Flags,
+        key: &[u8],
+        init_threads: u64,
+        hook: Option<InitHook>,
+    ) -> Result<Self> {
         let cache_ptr = unsafe { randomx_alloc_cache(flags.into()) };
         let cache_ptr = ptr::NonNull::new(cache_ptr).ok_or(Error::AllocCacheFailed)?;
         let mut this = Cache {
@@ -24,7 +43,7 @@ impl Cache {
                 return Err(Error::AllocDatasetFailed);
             }
         }
-        this.set_key(key, init_threads)?;
+        this.set_key_with_init_hook(key, init_threads, hook)?;
         Ok(this)
     }
 
@@ -41,11 +60,40 @@ impl Cache {
     }
 
     pub fn set_key(&mut self, key: &[u8], threads: u64) -> Result<()> {
+        self.set_key_with_init_hook(key, threads, None)
+    }
+
+    /// Like `set_key`, but runs `hook` on each thread initializing the cache and dataset.
+    pub fn set_key_with_init_hook(
+        &mut self,
+        key: &[u8],
+        threads: u64,
+        hook: Option<InitHook>,
+    ) -> Result<()> {
         if self.dataset_ptr.is_some() && threads == 0 {
             return Err(Error::ZeroInitThreads);
         }
-        unsafe {
-            randomx_init_cache(self.cache_ptr.as_ptr(), key.as_ptr() as *const _, key.len());
+        match &hook {
+            Some(hook) => {
+                let hook = hook.clone();
+                let key = key.to_vec();
+                let cache_ptr = UnsafeSend(self.cache_ptr);
+                std::thread::spawn(move || {
+                    hook(0);
+                    unsafe {
+                        randomx_init_cache(
+                            cache_ptr.0.as_ptr(),
+                            key.as_ptr() as *const _,
+                            key.len(),
+                        );
+                    }
+                })
+                .join()
+                .map_err(|_| Error::ThreadPanic)?;
+            }
+            None => unsafe {
+                randomx_init_cache(self.cache_ptr.as_ptr(), key.as_ptr() as *const _, key.len());
+            },
         }
         if let Some(dataset_ptr) = self.dataset_ptr {
             let count = unsafe { randomx_dataset_item_count() };
@@ -59,11 +107,16 @@ impl Cache {
                 if i < remainder {
                     num += 1;
                 }
-                struct UnsafeSend(ptr::NonNull<randomx_dataset>, ptr::NonNull<randomx_cache>);
-                unsafe impl Send for UnsafeSend {}
-                let ptrs = UnsafeSend(dataset_ptr, self.cache_ptr);
-                threads.push(std::thread::spawn(move || unsafe {
-                    randomx_init_dataset(ptrs.0.as_ptr(), ptrs.1.as_ptr(), start, num);
+                let ptrs = UnsafeSend((dataset_ptr, self.cache_ptr));
+                let hook = hook.clone();
+                threads.push(std::thread::spawn(move || {
+                    let (dataset_ptr, cache_ptr) = ptrs.0;
+                    if let Some(hook) = hook {
+                        hook(i);
+                    }
+                    unsafe {
+                        randomx_init_dataset(dataset_ptr.as_ptr(), cache_ptr.as_ptr(), start, num);
+                    }
                 }));
             }
             // Delay errors to make sure that threads finish first, delaying Drop impl.
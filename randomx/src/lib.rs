@@ -7,7 +7,7 @@ mod vm;
 #[cfg(test)]
 mod tests;
 
-pub use cache::Cache;
+pub use cache::{Cache, InitHook};
 pub use error::Error;
 pub use flags::Flags;
 pub use hash_chain::HashChain;
@@ -1,4 +1,5 @@
-use crate::{Cache, Flags};
+use crate::{Cache, Flags, InitHook};
+use std::sync::{Arc, Mutex};
 
 #[test]
 fn init_cache_only() {
@@ -21,3 +22,21 @@ fn init_multi_core() {
     flags.set_full_mem(true);
     Cache::new(flags, &[1; 32], 16).unwrap();
 }
+
+#[test]
+fn init_hook_runs_on_init_threads() {
+    let mut flags = Flags::recommended();
+    flags.set_full_mem(false);
+    let main = std::thread::current().id();
+    let runs = Arc::new(Mutex::new(Vec::new()));
+    let runs2 = runs.clone();
+    let hook: InitHook = Arc::new(move |i| {
+        assert_ne!(std::thread::current().id(), main);
+        runs2.lock().unwrap().push(i);
+    });
+    let mut cache = Cache::with_init_hook(flags, &[1; 32], 0, Some(hook.clone())).unwrap();
+    cache
+        .set_key_with_init_hook(&[2; 32], 0, Some(hook))
+        .unwrap();
+    assert_eq!(*runs.lock().unwrap(), vec![0, 0]);
+}
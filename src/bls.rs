@@ -1,5 +1,5 @@
 use amcl::{
-    bls381::{big::Big, bls381::utils},
+    bls381::{big::Big, bls381::utils, ecp2::ECP2, pair},
     errors::AmclError,
 };
 use log::trace;
@@ -18,7 +18,7 @@ impl SecretKey {
 
     pub fn sign(&self, msg: &[u8]) -> [u8; SIG_SIZE] {
         let hash = utils::hash_to_curve_g1(msg, DST);
-        let signature = amcl::bls381::pair::g1mul(&hash, &self.0);
+        let signature = pair::g1mul(&hash, &self.0);
         let sig = utils::serialize_g1(&signature);
         trace!(
             "signing {} -> {}",
@@ -30,7 +30,29 @@ impl SecretKey {
     }
 
     pub fn get_public_key(&self) -> [u8; 96] {
-        let point = amcl::bls381::pair::g2mul(&amcl::bls381::ecp2::ECP2::generator(), &self.0);
+        let point = pair::g2mul(&ECP2::generator(), &self.0);
         utils::serialize_g2(&point)
     }
 }
+
+#[derive(Clone)]
+pub struct PublicKey(ECP2);
+
+impl PublicKey {
+    pub fn new(bytes: &[u8]) -> Result<PublicKey, AmclError> {
+        utils::deserialize_g2(bytes).map(PublicKey)
+    }
+
+    /// Whether `sig` is our signature of `msg`, which a malformed signature isn't.
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        let sig = match utils::deserialize_g1(sig) {
+            Ok(sig) => sig,
+            Err(_) => return false,
+        };
+        let hash = utils::hash_to_curve_g1(msg, DST);
+        // e(sig, -g) * e(hash, key) is one exactly when e(sig, g) = e(hash, key)
+        let mut neg_generator = ECP2::generator();
+        neg_generator.neg();
+        pair::fexp(&pair::ate2(&neg_generator, &sig, &self.0, &hash)).is_unity()
+    }
+}
@@ -1,24 +1,120 @@
+use crate::{placement::CoreList, rpc::Endpoint};
 use randomx::Flags;
+use std::{io, path::PathBuf};
 
 #[derive(structopt::StructOpt, Clone)]
 pub struct Opts {
     /// The RPC address and port.
-    #[structopt(short = "r", long = "rpc", default_value = "localhost:5133")]
-    pub rpc: String,
+    /// Prefix with http:// to use JSON-RPC over HTTP instead of a raw TCP stream (tcp://),
+    /// or use unix:///path/to/socket to connect to a node on this host over a Unix domain socket.
+    /// May be specified multiple times, in order of preference.
+    /// The miner switches to the next healthy node if the current one stops answering
+    /// or falls behind, and back once a preferred node recovers.
+    #[structopt(
+        short = "r",
+        long = "rpc",
+        default_value = "localhost:5133",
+        number_of_values = 1
+    )]
+    pub rpc: Vec<Endpoint>,
+    /// A file containing a token to authenticate to HTTP RPC nodes with.
+    /// Takes precedence over the MEROS_RPC_TOKEN environment variable.
+    #[structopt(long = "rpc-token-file", parse(from_os_str))]
+    pub rpc_token_file: Option<PathBuf>,
+    /// How often to check the height of each RPC node, in seconds.
+    /// Only matters if multiple RPC nodes are specified.
+    #[structopt(long = "rpc-health-check-interval", default_value = "30")]
+    pub rpc_health_check_interval: u64,
+    /// How long to wait on the node to connect, accept a request, or answer it, in seconds.
+    /// A node which takes longer is considered stalled, and the miner reconnects.
+    /// 0 waits forever.
+    #[structopt(long = "rpc-timeout", default_value = "30")]
+    pub rpc_timeout: u64,
+    /// How many times to try connecting or making an RPC call before giving up.
+    /// Unlimited by default, leaving --rpc-retry-max-elapsed to decide.
+    #[structopt(long = "rpc-retry-max-attempts")]
+    pub rpc_retry_max_attempts: Option<u32>,
+    /// How long to keep retrying an unreachable or misbehaving node before giving up, in seconds.
+    /// 0, the default, retries forever, riding out node restarts and outages however long they take.
+    #[structopt(long = "rpc-retry-max-elapsed", default_value = "0")]
+    pub rpc_retry_max_elapsed: u64,
+    /// The most to wait between retries, in seconds.
+    /// Waits start short and double after each failure, up to this.
+    #[structopt(long = "rpc-retry-max-backoff", default_value = "30")]
+    pub rpc_retry_max_backoff: u64,
+    /// A file to record every RPC message sent and received in, as JSON lines.
+    /// Pass it to --rpc as replay:///path/to/file to answer requests from the recording.
+    #[structopt(long = "rpc-record", parse(from_os_str))]
+    pub rpc_record: Option<PathBuf>,
+    /// Where to keep a record of every block found, so it can be published again if need be.
+    #[structopt(
+        long = "journal",
+        default_value = "found-blocks.jsonl",
+        parse(from_os_str)
+    )]
+    pub journal: PathBuf,
+    /// How long to keep publishing found blocks for after Ctrl-C or SIGTERM, in seconds.
+    /// The miner exits anyways after that, with a nonzero status. A second signal exits right away.
+    #[structopt(long = "shutdown-timeout", default_value = "10")]
+    pub shutdown_timeout: u64,
+    // The thread counts are only checked when mining, so subcommands can go without them
     /// The number of threads to use to initialize RandomX.
-    /// Only matters on startup and on RandomX key change.
-    #[structopt(short = "i", long = "randomx-init-threads")]
+    /// Only matters on startup and on RandomX key change. Required to mine.
+    #[structopt(
+        short = "i",
+        long = "randomx-init-threads",
+        default_value = "0",
+        hide_default_value = true
+    )]
     pub randomx_init_threads: u64,
-    /// The number of threads to use for RandomX. Must be even.
-    #[structopt(short = "t", long = "randomx-threads")]
+    /// The number of threads to initialize the upcoming RandomX key with, in the background.
+    /// The key is known a dozen blocks before it's used, so a thread alongside mining is plenty.
+    /// 0 waits until the key is in use, then initializes it with --randomx-init-threads.
+    #[structopt(long = "randomx-precompute-threads", default_value = "1")]
+    pub randomx_precompute_threads: u64,
+    /// The number of threads to use for RandomX. Required to mine.
+    #[structopt(
+        short = "t",
+        long = "randomx-threads",
+        default_value = "0",
+        hide_default_value = true
+    )]
     pub randomx_threads: usize,
-    /// The number of threads to use for BLS signing.
-    #[structopt(short = "b", long = "bls-threads")]
+    /// The number of threads to use for BLS signing. Required to mine.
+    #[structopt(
+        short = "b",
+        long = "bls-threads",
+        default_value = "0",
+        hide_default_value = true
+    )]
     pub bls_threads: usize,
+    /// If threads should move between BLS signing and RandomX as needed to keep both busy,
+    /// starting from --bls-threads and --randomx-threads, and keeping their total.
+    #[structopt(long = "auto-balance")]
+    pub auto_balance: bool,
+    /// The cores to pin RandomX threads to, like 0-7,16-23, taking turns if there are more threads.
+    /// With --auto-balance, threads are pinned to --bls-cores then these, whichever they run.
+    #[structopt(long = "randomx-cores")]
+    pub randomx_cores: Option<CoreList>,
+    /// The cores to pin BLS signing threads to, like 8-11, taking turns if there are more threads.
+    #[structopt(long = "bls-cores")]
+    pub bls_cores: Option<CoreList>,
+    /// If each NUMA node should get its own copy of the RandomX dataset, initialized on the node,
+    /// with each RandomX thread pinned to a node and hashing with its copy.
+    /// Takes a dataset's worth of memory per node. Only supported on Linux.
+    #[structopt(long = "randomx-numa")]
+    pub randomx_numa: bool,
     /// If large pages should be used for RandomX.
     /// Requires special configuration at the OS level.
     #[structopt(short = "l", long = "randomx-large-pages")]
     pub randomx_large_pages: bool,
+    /// If RandomX should run in light mode, without the full memory dataset.
+    /// Hashing is much slower, but only around 256 MiB of memory is needed.
+    #[structopt(long = "randomx-light-mode")]
+    pub randomx_light_mode: bool,
+    /// A seed for where each template's nonces start, so runs can be reproduced. Random by default.
+    #[structopt(long = "nonce-seed")]
+    pub nonce_seed: Option<u64>,
     /// If the hash rate should be logged every 30 seconds.
     #[structopt(short = "o", long = "output-hash-rate")]
     pub output_hash_rate: bool,
@@ -29,12 +125,79 @@ pub struct Opts {
     /// This is a target for future improvement.
     #[structopt(short = "k", long = "randomx-stop-for-rekey")]
     pub randomx_stop_for_rekey: bool,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Things to do instead of mining
+#[derive(structopt::StructOpt, Clone)]
+pub enum Command {
+    /// Looks through or resubmits the blocks in the found block journal.
+    Journal(JournalCommand),
+    /// Measures how fast this machine mines with the thread options, without a node.
+    Bench {
+        /// How long to measure each stage, and the pipeline, for, in seconds.
+        #[structopt(long = "duration", default_value = "10")]
+        duration: u64,
+        /// Also measure every other split of the same number of threads
+        /// between BLS signing and RandomX, end to end.
+        #[structopt(long = "sweep")]
+        sweep: bool,
+        /// Prints the results as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+    },
+    /// Recomputes a block's hashes in RandomX light mode, and checks its signature and difficulty.
+    Verify {
+        /// The block header in hex, without the nonce and signature.
+        #[structopt(long = "header")]
+        header: String,
+        /// The nonce, which follows the header in the first hash's input.
+        #[structopt(long = "nonce")]
+        nonce: u32,
+        /// The BLS signature of the first hash, in hex.
+        #[structopt(long = "signature")]
+        signature: String,
+        /// The RandomX key in hex.
+        #[structopt(long = "key")]
+        key: String,
+        /// The difficulty to check the block's hash against.
+        #[structopt(long = "difficulty")]
+        difficulty: Option<u64>,
+        /// The miner's BLS public key in hex, to check the signature with.
+        /// By default, the one in the header, if it names a new miner.
+        #[structopt(long = "miner-key")]
+        miner_key: Option<String>,
+    },
+}
+
+#[derive(structopt::StructOpt, Clone)]
+pub enum JournalCommand {
+    /// Lists every block found, and what became of publishing it.
+    List,
+    /// Publishes blocks from the journal again, using the --rpc options.
+    Resubmit {
+        /// The hashes of the blocks to publish.
+        /// By default, every block which was never confirmed published, rejected or expired.
+        hashes: Vec<String>,
+    },
 }
 
 impl Opts {
+    pub fn get_rpc_token(&self) -> io::Result<Option<String>> {
+        if let Some(path) = &self.rpc_token_file {
+            return Ok(Some(std::fs::read_to_string(path)?.trim().to_owned()));
+        }
+        match std::env::var("MEROS_RPC_TOKEN") {
+            Ok(token) => Ok(Some(token)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
+
     pub fn get_randomx_flags(&self) -> Flags {
         let mut flags = Flags::recommended();
-        flags.set_full_mem(true);
+        flags.set_full_mem(!self.randomx_light_mode);
         flags.set_large_pages(self.randomx_large_pages);
         flags
     }
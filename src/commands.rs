@@ -0,0 +1,248 @@
+//! Subcommands, which work with the node or the journal instead of mining.
+
+use crate::{
+    bls::{PublicKey, SIG_SIZE},
+    cli::{Command, JournalCommand, Opts},
+    journal::{self, Event, Journal, Status},
+    rpc::{Rpc, RpcClientError},
+    template::{BlockHeader, HeaderMiner},
+    threads::bench,
+    verify::verify,
+};
+use eyre::{eyre, Report, WrapErr};
+use randomx::{Cache, Vm};
+use std::{sync::Arc, time::Duration};
+
+pub fn run(opts: Opts, command: Command) -> Result<(), Report> {
+    match command {
+        Command::Journal(JournalCommand::List) => list_journal(&opts),
+        Command::Journal(JournalCommand::Resubmit { hashes }) => resubmit(opts, &hashes),
+        Command::Bench {
+            duration,
+            sweep,
+            json,
+        } => run_bench(&opts, duration, sweep, json),
+        Command::Verify {
+            header,
+            nonce,
+            signature,
+            key,
+            difficulty,
+            miner_key,
+        } => verify_block(
+            &opts,
+            &header,
+            nonce,
+            &signature,
+            &key,
+            difficulty,
+            miner_key.as_deref(),
+        ),
+    }
+}
+
+fn decode_hex(what: &str, s: &str) -> Result<Vec<u8>, Report> {
+    hex::decode(s.trim()).wrap_err_with(|| format!("Invalid {} hex", what))
+}
+
+fn verify_block(
+    opts: &Opts,
+    header: &str,
+    nonce: u32,
+    signature: &str,
+    key: &str,
+    difficulty: Option<u64>,
+    miner_key: Option<&str>,
+) -> Result<(), Report> {
+    let header = decode_hex("header", header)?;
+    let parsed = BlockHeader::parse(&header).wrap_err("Invalid header")?;
+    let sig_bytes = decode_hex("signature", signature)?;
+    if sig_bytes.len() != SIG_SIZE {
+        return Err(eyre!("The signature must be {} bytes", SIG_SIZE));
+    }
+    let mut signature = [0u8; SIG_SIZE];
+    signature.copy_from_slice(&sig_bytes);
+    let key = decode_hex("RandomX key", key)?;
+    let miner_key = match (miner_key, parsed.miner) {
+        (Some(miner_key), _) => Some(decode_hex("miner key", miner_key)?),
+        (None, HeaderMiner::Key(miner_key)) => Some(miner_key.to_vec()),
+        (None, HeaderMiner::Nick(_)) => None,
+    };
+    if miner_key.is_none() && difficulty.is_none() {
+        return Err(eyre!(
+            "The header names its miner by nickname, so pass --miner-key, --difficulty or both"
+        ));
+    }
+    let public_key = match &miner_key {
+        Some(miner_key) => {
+            Some(PublicKey::new(miner_key).map_err(|err| eyre!("Invalid miner key: {:?}", err))?)
+        }
+        None => None,
+    };
+
+    let mut flags = opts.get_randomx_flags();
+    flags.set_full_mem(false);
+    let cache = Cache::new(flags, &key, opts.randomx_init_threads)
+        .wrap_err("Failed to initialize RandomX")?;
+    let mut vm = Vm::new(Arc::new(cache)).wrap_err("Failed to create RandomX VM")?;
+    let verification = verify(
+        &mut vm,
+        &header,
+        nonce,
+        &signature,
+        public_key.as_ref(),
+        difficulty,
+    );
+
+    let check = |passed: Option<bool>| match passed {
+        Some(true) => "valid",
+        Some(false) => "INVALID",
+        None => "unchecked",
+    };
+    println!("header           {}", hex::encode_upper(&header));
+    println!("nonce            {}", nonce);
+    println!(
+        "first input      {}",
+        hex::encode_upper(&verification.first_input)
+    );
+    println!(
+        "first hash       {}",
+        hex::encode_upper(verification.first_hash)
+    );
+    match &miner_key {
+        Some(miner_key) => println!("miner key        {}", hex::encode_upper(miner_key)),
+        None => println!("miner key        unknown, the header names its miner by nickname"),
+    }
+    println!(
+        "signature        {} {}",
+        hex::encode_upper(&signature as &[u8]),
+        check(verification.signature_valid),
+    );
+    println!(
+        "second input     {}",
+        hex::encode_upper(&verification.second_input)
+    );
+    println!(
+        "second hash      {}",
+        hex::encode_upper(verification.second_hash)
+    );
+    if let (Some(difficulty), Some(max_hash)) = (difficulty, verification.max_hash) {
+        println!("difficulty       {}", difficulty);
+        println!("max hash         {}", hex::encode_upper(max_hash));
+        println!(
+            "second hash      {} the max hash",
+            match verification.meets_difficulty {
+                Some(true) => "below",
+                _ => "NOT below",
+            },
+        );
+    }
+    if verification.valid() {
+        println!("valid");
+        Ok(())
+    } else {
+        Err(eyre!("The block is invalid"))
+    }
+}
+
+fn run_bench(opts: &Opts, duration: u64, sweep: bool, json: bool) -> Result<(), Report> {
+    let results = bench::run(opts, Duration::from_secs(duration), sweep)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+        return Ok(());
+    }
+    println!(
+        "with {} BLS and {} RandomX threads, per second:",
+        results.bls_threads, results.randomx_threads,
+    );
+    println!("first hashes   {:>10.1}", results.first_hashes);
+    println!("signatures     {:>10.1}", results.signatures);
+    println!("second hashes  {:>10.1}", results.second_hashes);
+    println!("end to end     {:>10.1}", results.end_to_end);
+    if !results.sweep.is_empty() {
+        println!("end to end by split, per second:");
+        for split in &results.sweep {
+            println!(
+                "{:>4} BLS {:>4} RandomX  {:>10.1}",
+                split.bls_threads, split.randomx_threads, split.end_to_end,
+            );
+        }
+    }
+    Ok(())
+}
+
+fn read_journal(opts: &Opts) -> Result<Vec<journal::Entry>, Report> {
+    journal::read(&opts.journal)
+        .wrap_err_with(|| format!("Failed to read journal {}", opts.journal.display()))
+}
+
+fn list_journal(opts: &Opts) -> Result<(), Report> {
+    for entry in read_journal(opts)? {
+        let block = &entry.block;
+        println!(
+            "{} height {} template {} hash {} {}",
+            block.time,
+            block.height,
+            block.template_id,
+            block.hash_hex(),
+            entry.status,
+        );
+    }
+    Ok(())
+}
+
+fn resubmit(opts: Opts, hashes: &[String]) -> Result<(), Report> {
+    let entries = read_journal(&opts)?;
+    let selected = if hashes.is_empty() {
+        entries
+            .into_iter()
+            .filter(|e| e.status == Status::Unpublished)
+            .collect()
+    } else {
+        hashes
+            .iter()
+            .map(|hash| {
+                entries
+                    .iter()
+                    .rev()
+                    .find(|e| e.block.hash_hex().eq_ignore_ascii_case(hash))
+                    .cloned()
+                    .ok_or_else(|| eyre!("No block with hash {} in the journal", hash))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    if selected.is_empty() {
+        println!("Nothing to resubmit");
+        return Ok(());
+    }
+    let mut journal = Journal::open(&opts.journal)
+        .wrap_err_with(|| format!("Failed to open journal {}", opts.journal.display()))?;
+    let mut rpc = Rpc::connect(opts).wrap_err("Failed to connect to RPC")?;
+    for entry in selected {
+        let block = entry.block;
+        let params = (block.template_id, hex::encode_upper(block.contents()));
+        let hash = block.hash.clone();
+        let event = match rpc.single_request::<_, bool>("merit_publishBlock", params) {
+            Ok(true) => Event::Published { hash },
+            Ok(false) => Event::Rejected {
+                hash,
+                reason: "unknown reason".to_owned(),
+            },
+            Err(RpcClientError::Rpc(err)) => Event::Rejected {
+                hash,
+                reason: err.to_string(),
+            },
+            Err(err) => return Err(err).wrap_err("Failed to publish block"),
+        };
+        match &event {
+            Event::Rejected { reason, .. } => {
+                println!("{} rejected: {}", block.hash_hex(), reason)
+            }
+            _ => println!("{} published", block.hash_hex()),
+        }
+        journal
+            .append(&event)
+            .wrap_err("Failed to write to journal")?;
+    }
+    Ok(())
+}
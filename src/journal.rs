@@ -0,0 +1,159 @@
+//! A local, append-only record of every block we found, so none are lost if publishing fails.
+//!
+//! Each line is a JSON event: a block being found, or the outcome of publishing it.
+//! Events are synced to disk as they're written, before anything is sent to the node.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Everything needed to publish a found block again, plus where it came from.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FoundBlock {
+    pub template_id: i64,
+    #[serde(with = "hex")]
+    pub header: Vec<u8>,
+    pub nonce: u32,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+    #[serde(with = "hex")]
+    pub hash: Vec<u8>,
+    pub seq: usize,
+    pub height: usize,
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl FoundBlock {
+    /// The block as `merit_publishBlock` takes it: the header, nonce and signature.
+    pub fn contents(&self) -> Vec<u8> {
+        let mut contents = self.header.clone();
+        contents.extend(&self.nonce.to_le_bytes());
+        contents.extend(&self.signature);
+        contents
+    }
+
+    pub fn hash_hex(&self) -> String {
+        hex::encode_upper(&self.hash)
+    }
+}
+
+/// A line of the journal. Outcomes refer back to the found block by its hash.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    Found(FoundBlock),
+    /// The node accepted the block.
+    Published {
+        #[serde(with = "hex")]
+        hash: Vec<u8>,
+    },
+    /// The node refused the block, so publishing it again won't help.
+    Rejected {
+        #[serde(with = "hex")]
+        hash: Vec<u8>,
+        reason: String,
+    },
+    /// The chain moved past the block's template before it could be published.
+    Expired {
+        #[serde(with = "hex")]
+        hash: Vec<u8>,
+    },
+}
+
+/// The latest outcome of publishing a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// Not published yet, or the miner stopped before hearing back.
+    Unpublished,
+    Published,
+    Rejected(String),
+    Expired,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Unpublished => write!(f, "unpublished"),
+            Status::Published => write!(f, "published"),
+            Status::Rejected(reason) => write!(f, "rejected ({})", reason),
+            Status::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub block: FoundBlock,
+    pub status: Status,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> io::Result<Journal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal { file })
+    }
+
+    /// Only returns once the event is on disk.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+/// Reads every found block in the journal, in the order they were found, with their status.
+/// A missing journal is just an empty one.
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut entries: Vec<Entry> = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} of {}: {}", i + 1, path.display(), err),
+            )
+        })?;
+        let (hash, status) = match event {
+            Event::Found(block) => {
+                entries.push(Entry {
+                    block,
+                    status: Status::Unpublished,
+                });
+                continue;
+            }
+            Event::Published { hash } => (hash, Status::Published),
+            Event::Rejected { hash, reason } => (hash, Status::Rejected(reason)),
+            Event::Expired { hash } => (hash, Status::Expired),
+        };
+        if let Some(entry) = entries.iter_mut().rev().find(|e| e.block.hash == hash) {
+            entry.status = status;
+        }
+    }
+    Ok(entries)
+}
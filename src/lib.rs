@@ -1,7 +1,15 @@
-//! Used exclusively by benches and tests
+//! The miner itself, shared by the binary, benches, and tests
 
 pub mod bls;
+pub mod cli;
+pub mod commands;
+pub mod journal;
+pub mod placement;
+pub mod rpc;
+pub mod template;
+pub mod threads;
 pub mod utils;
+pub mod verify;
 
 #[cfg(test)]
 mod tests;
@@ -1,12 +1,62 @@
+use crossbeam_channel::{bounded, select};
+use meroxidizer::{
+    cli, commands,
+    threads::{self, ShutdownError},
+};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
-mod bls;
-mod cli;
-mod rpc;
-mod threads;
+/// Exit status when shutting down took too long, or a thread panicked.
+const EXIT_UNCLEAN: i32 = 2;
 
 fn main() {
     env_logger::init();
-    let opts = cli::Opts::from_args();
-    let _ = threads::start(opts).join();
+    let mut opts = cli::Opts::from_args();
+    if let Some(command) = opts.command.take() {
+        if let Err(err) = commands::run(opts, command) {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let shutdown_timeout = Duration::from_secs(opts.shutdown_timeout);
+    // Set before starting, which can take minutes, so a signal meanwhile stops mining once it starts
+    let (signal_send, signals) = bounded(1);
+    let mut signalled = false;
+    ctrlc::set_handler(move || {
+        if signalled {
+            eprintln!("Exiting without finishing shutdown");
+            std::process::exit(EXIT_UNCLEAN);
+        }
+        signalled = true;
+        let _ = signal_send.send(());
+    })
+    .expect("Failed to set signal handler");
+    let miner = match threads::start(opts) {
+        Ok(miner) => miner,
+        Err(err) => {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+    };
+    select! {
+        recv(signals) -> _ => {},
+        recv(miner.rpc_exited()) -> _ => {},
+        recv(miner.gave_up()) -> _ => {},
+    }
+    match miner.shutdown(Instant::now() + shutdown_timeout) {
+        Ok(()) => {}
+        Err(ShutdownError::Rpc(err)) => {
+            eprintln!("Stopped mining, giving up on RPC: {}", err);
+            std::process::exit(1);
+        }
+        Err(ShutdownError::GaveUp(name)) => {
+            eprintln!("Stopped mining, the {} thread kept failing", name);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to shut down cleanly: {}", err);
+            std::process::exit(EXIT_UNCLEAN);
+        }
+    }
 }
@@ -0,0 +1,263 @@
+//! Which cores the worker threads run on, and which NUMA node's copy of RandomX they hash with.
+//!
+//! Memory ends up on the NUMA node of the core which first writes to it, so with a copy per node,
+//! each copy is initialized by threads pinned to its node, and each RandomX thread stays on one.
+
+use crate::cli::Opts;
+use core_affinity::CoreId;
+use eyre::{eyre, Report, WrapErr};
+use log::info;
+use randomx::{Cache, Flags, InitHook};
+use std::{fmt, fs, io, str::FromStr, sync::Arc};
+
+/// A list of cores, like `0-3,8,10-11`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoreList(pub Vec<usize>);
+
+impl FromStr for CoreList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CoreList, String> {
+        let mut cores = Vec::new();
+        for part in s.trim().split(',') {
+            let parse = |n: &str| {
+                n.parse::<usize>()
+                    .map_err(|_| format!("invalid core {:?} in {:?}", n, s))
+            };
+            let mut range = part.splitn(2, '-');
+            let first = parse(range.next().unwrap())?;
+            let last = match range.next() {
+                Some(last) => parse(last)?,
+                None => first,
+            };
+            if last < first {
+                return Err(format!("backwards core range {:?}", part));
+            }
+            cores.extend(first..=last);
+        }
+        Ok(CoreList(cores))
+    }
+}
+
+impl fmt::Display for CoreList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = &self.0[..];
+        while let Some(&first) = rest.first() {
+            let len = rest
+                .iter()
+                .enumerate()
+                .take_while(|&(i, &core)| core == first + i)
+                .count();
+            if first != self.0[0] {
+                write!(f, ",")?;
+            }
+            match len {
+                1 => write!(f, "{}", first)?,
+                _ => write!(f, "{}-{}", first, first + len - 1)?,
+            }
+            rest = &rest[len..];
+        }
+        Ok(())
+    }
+}
+
+/// The cores of each NUMA node with any, by node number.
+#[cfg(target_os = "linux")]
+fn numa_nodes() -> io::Result<Vec<(usize, CoreList)>> {
+    let mut nodes = Vec::new();
+    for entry in fs::read_dir("/sys/devices/system/node")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let node = match name.to_str().filter(|n| n.starts_with("node")) {
+            Some(name) => match name[4..].parse() {
+                Ok(node) => node,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        let cpulist = fs::read_to_string(entry.path().join("cpulist"))?;
+        // Nodes with memory but no cores have an empty list
+        if cpulist.trim().is_empty() {
+            continue;
+        }
+        let cores = cpulist
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        nodes.push((node, cores));
+    }
+    nodes.sort_by_key(|&(node, _)| node);
+    Ok(nodes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn numa_nodes() -> io::Result<Vec<(usize, CoreList)>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "NUMA placement is only supported on Linux",
+    ))
+}
+
+fn pin(core: usize) {
+    core_affinity::set_for_current(CoreId { id: core });
+}
+
+pub struct Placement {
+    bls_cores: Vec<usize>,
+    randomx_cores: Vec<usize>,
+    /// With --randomx-numa, the number and cores of each node. Otherwise just one, with no cores.
+    nodes: Vec<(usize, CoreList)>,
+}
+
+impl Placement {
+    /// Checks the cores exist, and finds the NUMA nodes if they're needed.
+    pub fn new(opts: &Opts) -> Result<Placement, Report> {
+        let bls_cores = opts.bls_cores.clone().map_or_else(Vec::new, |l| l.0);
+        let randomx_cores = opts.randomx_cores.clone().map_or_else(Vec::new, |l| l.0);
+        if !bls_cores.is_empty() || !randomx_cores.is_empty() {
+            let available = core_affinity::get_core_ids()
+                .ok_or_else(|| eyre!("Failed to get the cores available to pin threads to"))?;
+            for &core in bls_cores.iter().chain(&randomx_cores) {
+                if !available.iter().any(|c| c.id == core) {
+                    return Err(eyre!("Core {} isn't available to pin threads to", core));
+                }
+            }
+        }
+        let nodes = if opts.randomx_numa {
+            let nodes = numa_nodes().wrap_err("Failed to find the NUMA nodes")?;
+            if nodes.is_empty() {
+                return Err(eyre!("Found no NUMA nodes with cores"));
+            }
+            for &core in &randomx_cores {
+                if !nodes.iter().any(|(_, cores)| cores.0.contains(&core)) {
+                    return Err(eyre!("RandomX core {} isn't on any NUMA node", core));
+                }
+            }
+            for (node, cores) in &nodes {
+                info!("NUMA node {} has cores {}", node, cores);
+            }
+            nodes
+        } else {
+            vec![(0, CoreList(Vec::new()))]
+        };
+        Ok(Placement {
+            bls_cores,
+            randomx_cores,
+            nodes,
+        })
+    }
+
+    /// Pins the current thread to `core`, or to a core of a node taking turns if it's None
+    /// and there's a copy per node. Returns the index of the node, to hash with its copy.
+    fn place(&self, name: &str, index: usize, core: Option<usize>) -> usize {
+        let (node, core) = match core {
+            Some(core) => {
+                let node = self
+                    .nodes
+                    .iter()
+                    .position(|(_, cores)| cores.0.contains(&core))
+                    .unwrap_or(0);
+                (node, Some(core))
+            }
+            None => {
+                let node = index % self.nodes.len();
+                let cores = &(self.nodes[node].1).0;
+                let core = if cores.is_empty() {
+                    None
+                } else {
+                    Some(cores[index / self.nodes.len() % cores.len()])
+                };
+                (node, core)
+            }
+        };
+        if let Some(core) = core {
+            pin(core);
+            if self.nodes.len() > 1 {
+                info!(
+                    "pinned {} thread {} to core {} on NUMA node {}",
+                    name, index, core, self.nodes[node].0,
+                );
+            } else {
+                info!("pinned {} thread {} to core {}", name, index, core);
+            }
+        }
+        node
+    }
+
+    /// Pins the `index`th signer, if there are --bls-cores.
+    pub fn pin_signer(&self, index: usize) {
+        if !self.bls_cores.is_empty() {
+            let core = self.bls_cores[index % self.bls_cores.len()];
+            pin(core);
+            info!("pinned signer thread {} to core {}", index, core);
+        }
+    }
+
+    /// Pins the `index`th RandomX thread, returning which node's copy of RandomX it should use.
+    pub fn pin_randomx(&self, index: usize) -> usize {
+        let core = if self.randomx_cores.is_empty() {
+            None
+        } else {
+            Some(self.randomx_cores[index % self.randomx_cores.len()])
+        };
+        self.place("RandomX", index, core)
+    }
+
+    /// Pins the `index`th thread moving between signing and RandomX, which keeps its core either way.
+    /// They take the --bls-cores then the --randomx-cores, as the signers start with the first indices.
+    pub fn pin_balanced(&self, index: usize) -> usize {
+        let cores: Vec<_> = self.bls_cores.iter().chain(&self.randomx_cores).collect();
+        let core = if cores.is_empty() {
+            None
+        } else {
+            Some(*cores[index % cores.len()])
+        };
+        self.place("balanced", index, core)
+    }
+
+    /// Pins the threads initializing the copy for `node` to its cores.
+    fn init_hook(&self, node: usize) -> Option<InitHook> {
+        let cores = (self.nodes[node].1).0.clone();
+        if cores.is_empty() {
+            return None;
+        }
+        Some(Arc::new(move |i| pin(cores[i as usize % cores.len()])))
+    }
+
+    /// Initializes a copy of RandomX for `key` on each node.
+    pub fn new_caches(
+        &self,
+        flags: Flags,
+        key: &[u8],
+        init_threads: u64,
+    ) -> Result<Vec<Arc<Cache>>, randomx::Error> {
+        (0..self.nodes.len())
+            .map(|node| {
+                if self.nodes.len() > 1 {
+                    info!("initializing RandomX on NUMA node {}..", self.nodes[node].0);
+                }
+                Cache::with_init_hook(flags, key, init_threads, self.init_hook(node)).map(Arc::new)
+            })
+            .collect()
+    }
+
+    /// Rekeys each node's copy of RandomX in place, which nothing else may be holding.
+    pub fn set_key(
+        &self,
+        caches: &mut [Arc<Cache>],
+        key: &[u8],
+        init_threads: u64,
+    ) -> Result<(), randomx::Error> {
+        for (node, cache) in caches.iter_mut().enumerate() {
+            if self.nodes.len() > 1 {
+                info!(
+                    "reinitializing RandomX on NUMA node {}..",
+                    self.nodes[node].0
+                );
+            }
+            Arc::get_mut(cache)
+                .expect("RandomX cache still in use with mining paused")
+                .set_key_with_init_hook(key, init_threads, self.init_hook(node))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Choosing between several nodes, in order of preference.
+
+use super::{
+    ConnectOptions, Connection, Endpoint, FullRequest, FullResponse, Notification, ProtocolError,
+    RpcClientError,
+};
+use crossbeam_channel::{unbounded, Sender};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{
+    fmt, io,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum SwitchReason {
+    Unresponsive(String),
+    Behind { height: usize, best: usize },
+    PreferredRecovered,
+}
+
+impl fmt::Display for SwitchReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SwitchReason::Unresponsive(err) => write!(f, "it stopped answering ({})", err),
+            SwitchReason::Behind { height, best } => {
+                write!(f, "it fell behind (height {} vs {})", height, best)
+            }
+            SwitchReason::PreferredRecovered => write!(f, "a preferred node recovered"),
+        }
+    }
+}
+
+type Heights = Mutex<Vec<Option<usize>>>;
+
+/// Asks a node for its height on a fresh connection.
+/// Probes aren't recorded, as they aren't part of the session with the current node.
+fn probe_height(endpoint: &Endpoint, token: Option<String>) -> Result<usize, RpcClientError> {
+    let (notify, _notifications) = unbounded();
+    let options = ConnectOptions {
+        token,
+        timeout: Some(PROBE_TIMEOUT),
+        recorder: None,
+        replays: Default::default(),
+    };
+    let mut conn = Connection::open(endpoint, &options, notify)?;
+    let req = FullRequest {
+        json_rpc: "2.0",
+        id: 0,
+        method: "merit_getHeight",
+        params: [(); 0],
+    };
+    conn.send(&req)?;
+    let message = conn.recv(Some(Instant::now() + PROBE_TIMEOUT))??;
+    FullResponse::deserialize(message)
+        .map_err(ProtocolError::Malformed)?
+        .into_result()
+}
+
+fn health_check(
+    addrs: Vec<Endpoint>,
+    token: Option<String>,
+    heights: Weak<Heights>,
+    interval: Duration,
+) {
+    loop {
+        let probed = addrs
+            .iter()
+            .map(|addr| match addr {
+                // A probe would take recorded responses the replayed session needs
+                Endpoint::Replay(_) => None,
+                addr => match probe_height(addr, token.clone()) {
+                    Ok(height) => Some(height),
+                    Err(err) => {
+                        debug!("health check of RPC node {} failed: {}", addr, err);
+                        None
+                    }
+                },
+            })
+            .collect();
+        match heights.upgrade() {
+            Some(heights) => *heights.lock() = probed,
+            None => return,
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+pub struct Endpoints {
+    addrs: Vec<Endpoint>,
+    options: ConnectOptions,
+    current: usize,
+    /// The height each node reported in the latest health check, if it answered.
+    heights: Arc<Heights>,
+}
+
+impl Endpoints {
+    /// Takes node addresses in order of preference.
+    /// With more than one, they're health checked every `health_interval` in the background.
+    pub fn new(
+        addrs: Vec<Endpoint>,
+        options: ConnectOptions,
+        health_interval: Duration,
+    ) -> Endpoints {
+        let heights = Arc::new(Mutex::new(vec![None; addrs.len()]));
+        if addrs.len() > 1 {
+            let addrs = addrs.clone();
+            let token = options.token.clone();
+            let heights = Arc::downgrade(&heights);
+            std::thread::spawn(move || health_check(addrs, token, heights, health_interval));
+        }
+        Endpoints {
+            addrs,
+            options,
+            current: 0,
+            heights,
+        }
+    }
+
+    pub fn addr(&self, i: usize) -> &Endpoint {
+        &self.addrs[i]
+    }
+
+    pub fn open(&self, i: usize, notify: Sender<Notification>) -> io::Result<Connection> {
+        Connection::open(&self.addrs[i], &self.options, notify)
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Node `i` first, then the rest by preference.
+    pub fn order_from(&self, i: usize) -> Vec<usize> {
+        let mut order = vec![i];
+        order.extend((0..self.addrs.len()).filter(|&j| j != i));
+        order
+    }
+
+    /// The other nodes by preference, then the current one as a last resort.
+    pub fn failover_order(&self) -> Vec<usize> {
+        let mut order: Vec<_> = (0..self.addrs.len())
+            .filter(|&j| j != self.current)
+            .collect();
+        order.push(self.current);
+        order
+    }
+
+    pub fn switch_to(&mut self, i: usize, reason: &SwitchReason) {
+        if i == self.current {
+            return;
+        }
+        warn!(
+            "switching RPC node from {} to {}: {}",
+            self.addrs[self.current], self.addrs[i], reason,
+        );
+        self.current = i;
+        // Don't act on health checks from before the switch
+        for height in self.heights.lock().iter_mut() {
+            *height = None;
+        }
+    }
+
+    /// Picks a better node than the current one based on the latest health check, if any.
+    pub fn choose(&self) -> Option<(usize, SwitchReason)> {
+        let heights = self.heights.lock();
+        let height = heights[self.current]?;
+        let best = heights.iter().filter_map(|h| *h).max()?;
+        if best > height {
+            let i = heights.iter().position(|h| *h == Some(best))?;
+            return Some((i, SwitchReason::Behind { height, best }));
+        }
+        let i = heights[..self.current]
+            .iter()
+            .position(|h| *h >= Some(height))?;
+        Some((i, SwitchReason::PreferredRecovered))
+    }
+}
@@ -0,0 +1,194 @@
+//! JSON-RPC over HTTP POST requests, on a keep-alive connection.
+
+use super::{connect_tcp, Inbox, Transport};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+pub struct HttpTransport {
+    host: String,
+    path: String,
+    token: Option<String>,
+    timeout: Option<Duration>,
+    /// Dropped when the server closes the connection, and reopened on the next request.
+    stream: Option<BufReader<TcpStream>>,
+    inbox: Inbox,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_line(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    if stream.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end().to_owned())
+}
+
+fn read_chunked(stream: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(stream)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("invalid HTTP chunk size {:?}", line)))?;
+        if size == 0 {
+            // Skip any trailers
+            while !read_line(stream)?.is_empty() {}
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..])?;
+        read_line(stream)?;
+    }
+}
+
+impl HttpTransport {
+    pub fn open(
+        host: &str,
+        path: &str,
+        token: Option<&str>,
+        timeout: Option<Duration>,
+        inbox: Inbox,
+    ) -> io::Result<HttpTransport> {
+        let mut transport = HttpTransport {
+            host: host.to_owned(),
+            path: path.to_owned(),
+            token: token.map(str::to_owned),
+            timeout,
+            stream: None,
+            inbox,
+        };
+        // Connect eagerly, so an unreachable node is noticed like it would be over TCP
+        transport.stream = Some(transport.connect()?);
+        Ok(transport)
+    }
+
+    /// Responses are read synchronously, so unlike streams, reads time out here too.
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let stream = connect_tcp(&self.host, self.timeout)?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        Ok(BufReader::new(stream))
+    }
+
+    fn write_request(&mut self, body: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        let mut req = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            self.path,
+            self.host,
+            body.len(),
+        );
+        if let Some(token) = &self.token {
+            req += &format!("Authorization: Bearer {}\r\n", token);
+        }
+        req += "\r\n";
+        let writer = stream.get_mut();
+        writer.write_all(req.as_bytes())?;
+        writer.write_all(body)
+    }
+
+    fn read_response(&mut self) -> io::Result<Vec<u8>> {
+        let stream = self.stream.as_mut().unwrap();
+        let status_line = read_line(stream)?;
+        let status = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| invalid_data(format!("invalid HTTP status line {:?}", status_line)))?;
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut close = false;
+        loop {
+            let line = read_line(stream)?;
+            if line.is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = parts.next().unwrap_or_default().trim();
+            match name.as_str() {
+                "content-length" => {
+                    content_length = Some(value.parse::<usize>().map_err(|_| {
+                        invalid_data(format!("invalid HTTP content length {:?}", value))
+                    })?);
+                }
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "connection" => close = value.eq_ignore_ascii_case("close"),
+                _ => {}
+            }
+        }
+        let body = if chunked {
+            read_chunked(stream)?
+        } else if let Some(len) = content_length {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body)?;
+            body
+        } else {
+            close = true;
+            let mut body = Vec::new();
+            stream.read_to_end(&mut body)?;
+            body
+        };
+        if close {
+            self.stream = None;
+        }
+        match status {
+            200..=299 => Ok(body),
+            401 | 403 => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("HTTP status {}, check the RPC token", status),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("HTTP status {}", status),
+            )),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let reused = self.stream.is_some();
+        if let Err(err) = self.write_request(message) {
+            self.stream = None;
+            // The server may have closed an idle keep-alive connection, so try once more.
+            // Only when writing fails though, as a request which was sent may have been acted on.
+            let retryable = match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => false,
+                _ => reused,
+            };
+            if !retryable {
+                return Err(err);
+            }
+            if let Err(err) = self.write_request(message) {
+                self.stream = None;
+                return Err(err);
+            }
+        }
+        let body = match self.read_response() {
+            Ok(body) => body,
+            Err(err) => {
+                self.stream = None;
+                return Err(err);
+            }
+        };
+        if !body.is_empty() {
+            self.inbox.deliver(serde_json::from_slice(&body));
+        }
+        Ok(())
+    }
+
+    fn supports_notifications(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,988 @@
+use crate::cli::Opts;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use failover::{Endpoints, SwitchReason};
+use hashbrown::{HashMap, HashSet};
+use log::{debug, error, info, warn};
+use record::{Direction, Recorder};
+use retry::{Backoff, RetryPolicy};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Deserializer, Value};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+mod failover;
+mod http;
+mod record;
+mod replay;
+mod retry;
+#[cfg(unix)]
+mod unix;
+
+pub type RequestId = u64;
+
+/// Node events which mean the block template may have changed.
+pub const TEMPLATE_EVENTS: [&str; 2] = ["merit_block", "merit_template"];
+
+#[derive(Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "code {}", self.code)?;
+        if let Some(message) = &self.message {
+            write!(f, " message {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// The node sent something that doesn't line up with what we asked for.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A response with an id we never sent, or without an id at all.
+    UnmatchedResponse(Option<RequestId>),
+    /// A response for a request that was already answered or abandoned.
+    DuplicateResponse(RequestId),
+    /// A message that isn't JSON, or isn't shaped like a JSON-RPC response.
+    Malformed(serde_json::Error),
+    /// A response that doesn't deserialize into what its request expects.
+    InvalidResponse(serde_json::Error),
+    /// A request we couldn't serialize.
+    InvalidRequest(serde_json::Error),
+    /// The request isn't in flight on the current connection, e.g. because we reconnected.
+    NotPending(RequestId),
+    /// The node answered a batch with a single error, as nodes without batch support do.
+    BatchUnsupported(RpcError),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnmatchedResponse(Some(id)) => {
+                write!(f, "got response for unknown request id {}", id)
+            }
+            ProtocolError::UnmatchedResponse(None) => write!(f, "got response without an id"),
+            ProtocolError::DuplicateResponse(id) => {
+                write!(f, "got duplicate response for request id {}", id)
+            }
+            ProtocolError::Malformed(err) => write!(f, "got malformed message: {}", err),
+            ProtocolError::InvalidResponse(err) => write!(f, "got invalid response: {}", err),
+            ProtocolError::InvalidRequest(err) => write!(f, "failed to serialize request: {}", err),
+            ProtocolError::NotPending(id) => write!(f, "request id {} is not pending", id),
+            ProtocolError::BatchUnsupported(err) => {
+                write!(f, "node rejected batch request: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Why an RPC call failed, which decides what to do about it.
+#[derive(Debug)]
+pub enum RpcClientError {
+    /// We couldn't reach the node, or the connection broke.
+    /// Reconnecting, possibly to another node, may help.
+    Connection(io::Error),
+    /// The node said something we didn't expect, so the connection can't be trusted anymore.
+    /// Reconnecting may help, but it may also be a node we're incompatible with.
+    Protocol(ProtocolError),
+    /// The node understood the request and returned an error for it.
+    /// The connection is fine, so reconnecting won't help.
+    Rpc(RpcError),
+    /// The node stopped answering for the given time, though the connection is still open.
+    /// Reconnecting, preferably to another node, should help.
+    Stalled(Duration),
+    /// We couldn't load something connecting needs, like the RPC token, described by the `str`.
+    /// Reconnecting won't help.
+    Setup(&'static str, io::Error),
+    /// A replayed session has no more recorded requests for the given method.
+    /// Reconnecting won't help, as it picks up the session where it left off.
+    ReplayExhausted(String),
+}
+
+impl RpcClientError {
+    /// If this calls for a new connection, rather than just failing the call.
+    pub fn should_reconnect(&self) -> bool {
+        match self {
+            RpcClientError::Connection(_)
+            | RpcClientError::Protocol(_)
+            | RpcClientError::Stalled(_) => true,
+            RpcClientError::Rpc(_)
+            | RpcClientError::Setup(..)
+            | RpcClientError::ReplayExhausted(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for RpcClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcClientError::Connection(err) => write!(f, "connection error: {}", err),
+            RpcClientError::Protocol(err) => write!(f, "protocol error: {}", err),
+            RpcClientError::Rpc(err) => write!(f, "JSON-RPC error: {}", err),
+            RpcClientError::Stalled(timeout) => {
+                write!(f, "node stalled, got nothing back for {:?}", timeout)
+            }
+            RpcClientError::Setup(what, err) => write!(f, "failed to {}: {}", what, err),
+            RpcClientError::ReplayExhausted(method) => {
+                write!(
+                    f,
+                    "replayed session has no more recorded requests for {}",
+                    method
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RpcClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcClientError::Connection(err) => Some(err),
+            RpcClientError::Protocol(err) => Some(err),
+            RpcClientError::Rpc(err) => Some(err),
+            RpcClientError::Stalled(_) => None,
+            RpcClientError::Setup(_, err) => Some(err),
+            RpcClientError::ReplayExhausted(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for RpcClientError {
+    fn from(err: io::Error) -> RpcClientError {
+        match err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<replay::Exhausted>())
+        {
+            Some(exhausted) => RpcClientError::ReplayExhausted(exhausted.0.clone()),
+            None => RpcClientError::Connection(err),
+        }
+    }
+}
+
+impl From<ProtocolError> for RpcClientError {
+    fn from(err: ProtocolError) -> RpcClientError {
+        RpcClientError::Protocol(err)
+    }
+}
+
+impl From<RpcError> for RpcClientError {
+    fn from(err: RpcError) -> RpcClientError {
+        RpcClientError::Rpc(err)
+    }
+}
+
+/// For errors reading from the node, which may either be the connection's fault or the node's.
+impl From<serde_json::Error> for RpcClientError {
+    fn from(err: serde_json::Error) -> RpcClientError {
+        if err.is_io() || err.is_eof() {
+            RpcClientError::Connection(err.into())
+        } else {
+            RpcClientError::Protocol(ProtocolError::Malformed(err))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FullRequest<'a, P> {
+    #[serde(rename = "jsonrpc")]
+    json_rpc: &'static str,
+    id: RequestId,
+    method: &'a str,
+    params: P,
+}
+
+#[derive(Deserialize)]
+struct FullResponse {
+    #[serde(default)]
+    id: Option<RequestId>,
+    #[serde(default)]
+    result: Value,
+    #[serde(default)]
+    error: Option<RpcError>,
+}
+
+impl FullResponse {
+    fn into_result<R: DeserializeOwned>(self) -> Result<R, RpcClientError> {
+        match self.error {
+            Some(error) => Err(error.into()),
+            None => serde_json::from_value(self.result)
+                .map_err(|err| ProtocolError::InvalidResponse(err).into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RpcMiningTarget {
+    pub id: i64,
+    #[serde(with = "hex")]
+    pub key: [u8; 32],
+    #[serde(with = "hex")]
+    pub header: Vec<u8>,
+    pub difficulty: u64,
+}
+
+/// The parts of a block from `merit_getBlock` we have any use for.
+#[derive(Debug, Deserialize)]
+pub struct RpcBlock {
+    #[serde(with = "hex")]
+    pub hash: [u8; 32],
+}
+
+/// A message the node sent on its own, rather than in response to a request.
+#[derive(Debug)]
+pub struct Notification {
+    pub method: String,
+    pub params: Value,
+}
+
+impl Notification {
+    fn parse(message: &Value) -> Option<Notification> {
+        let method = message.get("method")?.as_str()?;
+        if message.get("id").map(Value::is_null) == Some(false) {
+            return None;
+        }
+        Some(Notification {
+            method: method.to_owned(),
+            params: message.get("params").cloned().unwrap_or_default(),
+        })
+    }
+}
+
+type Message = Result<Value, serde_json::Error>;
+
+/// Where a transport delivers whatever the node sends back.
+struct Inbox {
+    messages: Sender<Message>,
+    notify: Sender<Notification>,
+    recorder: Option<Recorder>,
+}
+
+impl Inbox {
+    /// Notifications are split off as they arrive, so they can wake up a waiting caller.
+    /// Returns false once there's no point in reading any further.
+    fn deliver(&self, message: Message) -> bool {
+        if let (Some(recorder), Ok(message)) = (&self.recorder, &message) {
+            recorder.record(Direction::Received, message);
+        }
+        if let Ok(Some(notification)) = message.as_ref().map(Notification::parse) {
+            let _ = self.notify.send(notification);
+            return true;
+        }
+        let failed = message.is_err();
+        self.messages.send(message).is_ok() && !failed
+    }
+}
+
+/// Reads every JSON value off the stream until it errors or closes.
+fn read_messages<R: Read>(reader: R, inbox: Inbox) {
+    for message in Deserializer::from_reader(reader).into_iter() {
+        if !inbox.deliver(message) {
+            return;
+        }
+    }
+}
+
+/// Where to reach a node, as given to `--rpc`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// JSON values streamed back and forth over a TCP connection.
+    /// Used for addresses without a scheme.
+    Tcp(String),
+    /// Each request POSTed to `path` on `host`.
+    Http { host: String, path: String },
+    /// Like `Tcp`, but over a Unix domain socket, so access is limited by its file permissions.
+    Unix(PathBuf),
+    /// Not a node, but a session recorded with `--rpc-record` to answer from.
+    Replay(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Endpoint, String> {
+        let mut parts = s.splitn(2, "://");
+        let (scheme, rest) = match (parts.next(), parts.next()) {
+            (Some(scheme), Some(rest)) => (scheme, rest),
+            _ => return Ok(Endpoint::Tcp(s.to_owned())),
+        };
+        match scheme {
+            "tcp" => Ok(Endpoint::Tcp(rest.to_owned())),
+            "http" => {
+                let (host, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                let mut host = host.to_owned();
+                if !host.contains(':') || host.ends_with(']') {
+                    host += ":80";
+                }
+                Ok(Endpoint::Http {
+                    host,
+                    path: path.to_owned(),
+                })
+            }
+            "unix" if rest.is_empty() => Err("missing RPC socket path".to_owned()),
+            "unix" => Ok(Endpoint::Unix(PathBuf::from(rest))),
+            "replay" if rest.is_empty() => Err("missing RPC session path".to_owned()),
+            "replay" => Ok(Endpoint::Replay(PathBuf::from(rest))),
+            _ => Err(format!("unsupported RPC scheme {:?}", scheme)),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Endpoint::Http { host, path } => write!(f, "http://{}{}", host, path),
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+            Endpoint::Replay(path) => write!(f, "replay://{}", path.display()),
+        }
+    }
+}
+
+/// Carries requests to the node.
+/// Whatever the node sends back goes to the connection's message and notification channels.
+trait Transport: Send {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    /// If the node can send us messages on its own, as required for subscriptions.
+    fn supports_notifications(&self) -> bool {
+        true
+    }
+}
+
+/// A connected socket which JSON values can be streamed over in both directions.
+trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+
+    fn shutdown(&self) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Like `TcpStream::connect`, but giving up on each address after `timeout`.
+fn connect_tcp(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(addr),
+    };
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+/// Writes to a stream, with a background thread reading from it.
+struct StreamTransport<S: Stream>(S);
+
+impl<S: Stream> StreamTransport<S> {
+    /// Reads are left blocking, as the node may go quiet between notifications.
+    /// Waiting on responses is timed out by `Rpc` instead.
+    fn new(writer: S, timeout: Option<Duration>, inbox: Inbox) -> io::Result<StreamTransport<S>> {
+        writer.set_write_timeout(timeout)?;
+        let reader = writer.try_clone()?;
+        std::thread::spawn(move || read_messages(reader, inbox));
+        Ok(StreamTransport(writer))
+    }
+}
+
+impl<S: Stream> Transport for StreamTransport<S> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.0.write_all(message)
+    }
+}
+
+impl<S: Stream> Drop for StreamTransport<S> {
+    fn drop(&mut self) {
+        // Unblocks and ends the reader thread.
+        let _ = self.0.shutdown();
+    }
+}
+
+/// How to open connections, whichever node they're to.
+struct ConnectOptions {
+    token: Option<String>,
+    /// Applies to connecting, sending each request, and waiting for each response.
+    timeout: Option<Duration>,
+    recorder: Option<Recorder>,
+    replays: replay::Sessions,
+}
+
+struct Connection {
+    transport: Box<dyn Transport>,
+    incoming: Receiver<Message>,
+    timeout: Option<Duration>,
+    recorder: Option<Recorder>,
+}
+
+impl Connection {
+    fn open(
+        endpoint: &Endpoint,
+        options: &ConnectOptions,
+        notify: Sender<Notification>,
+    ) -> Result<Connection, io::Error> {
+        let (messages, incoming) = unbounded();
+        let timeout = options.timeout;
+        let recorder = options.recorder.as_ref().map(|r| r.for_node(endpoint));
+        let inbox = Inbox {
+            messages,
+            notify,
+            recorder: recorder.clone(),
+        };
+        let transport: Box<dyn Transport> = match endpoint {
+            Endpoint::Tcp(addr) => {
+                let stream = connect_tcp(addr, timeout)?;
+                Box::new(StreamTransport::new(stream, timeout, inbox)?)
+            }
+            Endpoint::Http { host, path } => Box::new(http::HttpTransport::open(
+                host,
+                path,
+                options.token.as_deref(),
+                timeout,
+                inbox,
+            )?),
+            Endpoint::Replay(path) => Box::new(replay::ReplayTransport::open(
+                path,
+                &options.replays,
+                inbox,
+            )?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let stream = unix::connect(path)?;
+                Box::new(StreamTransport::new(stream, timeout, inbox)?)
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Unix domain sockets aren't supported on this platform",
+                ));
+            }
+        };
+        Ok(Connection {
+            transport,
+            incoming,
+            timeout,
+            recorder,
+        })
+    }
+
+    fn send<T: Serialize>(&mut self, message: &T) -> Result<(), RpcClientError> {
+        let message = serde_json::to_value(message).map_err(ProtocolError::InvalidRequest)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Sent, &message);
+        }
+        let message = serde_json::to_vec(&message).map_err(ProtocolError::InvalidRequest)?;
+        self.transport
+            .send(&message)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    RpcClientError::Stalled(self.timeout.unwrap_or_default())
+                }
+                _ => err.into(),
+            })
+    }
+
+    /// Waits for the next message, giving up at `deadline` if there is one.
+    fn recv(&self, deadline: Option<Instant>) -> Result<Message, RpcClientError> {
+        let disconnected = || io::Error::from(io::ErrorKind::UnexpectedEof).into();
+        match deadline {
+            Some(deadline) => self
+                .incoming
+                .recv_deadline(deadline)
+                .map_err(|err| match err {
+                    RecvTimeoutError::Timeout => {
+                        RpcClientError::Stalled(self.timeout.unwrap_or_default())
+                    }
+                    RecvTimeoutError::Disconnected => disconnected(),
+                }),
+            None => self.incoming.recv().map_err(|_| disconnected()),
+        }
+    }
+}
+
+pub struct Rpc {
+    endpoints: Endpoints,
+    conn: Connection,
+    retry: RetryPolicy,
+    next_id: RequestId,
+    /// Requests sent on the current connection which haven't been answered yet.
+    in_flight: HashSet<RequestId>,
+    /// Responses which have been read but not yet taken by their caller.
+    responses: HashMap<RequestId, FullResponse>,
+    /// Requests nobody is waiting on anymore, whose responses are dropped when they arrive.
+    abandoned: HashSet<RequestId>,
+    notify: Sender<Notification>,
+    /// Outlives individual connections, so it can be handed out once.
+    notifications: Receiver<Notification>,
+    /// If we should resubscribe to `TEMPLATE_EVENTS` whenever we reconnect.
+    subscribed: bool,
+    /// If the current node supports batch requests, once we know.
+    supports_batches: Option<bool>,
+    /// The ids of the latest batch request, to recognize the node rejecting it.
+    last_batch: Vec<RequestId>,
+    /// How many times a node stopped answering without closing the connection.
+    stalls: usize,
+}
+
+impl Rpc {
+    /// Connects to the first node in `order` which accepts, switching to it if necessary.
+    fn open_connection(
+        endpoints: &mut Endpoints,
+        notify: &Sender<Notification>,
+        order: Vec<usize>,
+        mut reason: SwitchReason,
+    ) -> io::Result<Connection> {
+        let mut last_err = None;
+        for &i in &order {
+            match endpoints.open(i, notify.clone()) {
+                Ok(c) => {
+                    endpoints.switch_to(i, &reason);
+                    return Ok(c);
+                }
+                Err(err) => {
+                    error!(
+                        "error connecting to RPC node {}: {}",
+                        endpoints.addr(i),
+                        err
+                    );
+                    if i == endpoints.current() {
+                        reason = SwitchReason::Unresponsive(err.to_string());
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no RPC nodes specified")))
+    }
+
+    /// Connects to the most preferred node which accepts,
+    /// retrying per the retry policy if none do.
+    pub fn connect(opts: Opts) -> Result<Rpc, RpcClientError> {
+        let (notify, notifications) = unbounded();
+        let retry = RetryPolicy::from_opts(&opts);
+        let options = ConnectOptions {
+            token: opts
+                .get_rpc_token()
+                .map_err(|err| RpcClientError::Setup("load RPC token", err))?,
+            timeout: match opts.rpc_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            recorder: match &opts.rpc_record {
+                Some(path) => Some(
+                    Recorder::create(path)
+                        .map_err(|err| RpcClientError::Setup("open RPC recording", err))?,
+                ),
+                None => None,
+            },
+            replays: replay::Sessions::default(),
+        };
+        let mut endpoints = Endpoints::new(
+            opts.rpc,
+            options,
+            Duration::from_secs(opts.rpc_health_check_interval),
+        );
+        let mut backoff = retry.start();
+        let conn = loop {
+            let order = endpoints.order_from(0);
+            let reason = SwitchReason::Unresponsive("couldn't connect".to_owned());
+            match Self::open_connection(&mut endpoints, &notify, order, reason) {
+                Ok(conn) => break conn,
+                Err(err) => backoff.wait(err)?,
+            }
+        };
+        Ok(Rpc {
+            endpoints,
+            conn,
+            retry,
+            next_id: 0,
+            in_flight: HashSet::new(),
+            responses: HashMap::new(),
+            abandoned: HashSet::new(),
+            notify,
+            notifications,
+            subscribed: false,
+            supports_batches: None,
+            last_batch: Vec::new(),
+            stalls: 0,
+        })
+    }
+
+    /// Replaces the connection, abandoning anything still in flight.
+    /// Request ids keep counting up so stale responses can never be mistaken for new ones.
+    fn replace_connection(
+        &mut self,
+        order: Vec<usize>,
+        reason: SwitchReason,
+    ) -> Result<(), RpcClientError> {
+        let node = self.endpoints.current();
+        self.conn = Self::open_connection(&mut self.endpoints, &self.notify, order, reason)?;
+        self.in_flight.clear();
+        self.responses.clear();
+        self.abandoned.clear();
+        if self.endpoints.current() != node {
+            self.supports_batches = None;
+        }
+        Ok(())
+    }
+
+    fn reconnect(&mut self, order: Vec<usize>, reason: SwitchReason) -> Result<(), RpcClientError> {
+        self.replace_connection(order, reason)?;
+        if self.subscribed {
+            match self.try_subscribe() {
+                Ok(true) => {}
+                Ok(false) | Err(RpcClientError::Rpc(_)) => {
+                    warn!("node stopped accepting subscriptions, falling back to polling");
+                    self.subscribed = false;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconnects, starting with the nodes in `order`,
+    /// and failing over between all of them until `backoff` gives up.
+    fn recover(
+        &mut self,
+        mut order: Vec<usize>,
+        mut reason: SwitchReason,
+        backoff: &mut Backoff,
+    ) -> Result<(), RpcClientError> {
+        loop {
+            match self.reconnect(order, reason) {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    error!("error reconnecting to RPC: {}", err);
+                    reason = SwitchReason::Unresponsive(err.to_string());
+                    backoff.wait(err)?;
+                    order = self.endpoints.failover_order();
+                }
+            }
+        }
+    }
+
+    /// Runs `f`, reconnecting and retrying on connection and protocol errors
+    /// until the retry policy gives up.
+    /// JSON-RPC errors are returned right away, as the node already gave its answer.
+    fn with_retry<F, O>(&mut self, mut f: F) -> Result<O, RpcClientError>
+    where
+        F: FnMut(&mut Self) -> Result<O, RpcClientError>,
+    {
+        let mut backoff = self.retry.start();
+        loop {
+            let err = match f(self) {
+                Ok(x) => return Ok(x),
+                Err(err) if err.should_reconnect() => err,
+                Err(err) => return Err(err),
+            };
+            if let RpcClientError::Stalled(_) = err {
+                warn!("RPC node {} stalled, reconnecting: {}", self.node(), err);
+                self.stalls += 1;
+            } else {
+                error!("error making RPC call: {}", err);
+            }
+            let reason = SwitchReason::Unresponsive(err.to_string());
+            backoff.wait(err)?;
+            let order = self.endpoints.failover_order();
+            self.recover(order, reason, &mut backoff)?;
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> Result<(), RpcClientError> {
+        match message? {
+            Value::Array(batch) => batch
+                .into_iter()
+                .try_for_each(|response| self.handle_response(response)),
+            response => self.handle_response(response),
+        }
+    }
+
+    fn handle_response(&mut self, response: Value) -> Result<(), RpcClientError> {
+        let response = FullResponse::deserialize(response).map_err(ProtocolError::Malformed)?;
+        match response.id {
+            Some(id) if self.in_flight.remove(&id) => {
+                self.responses.insert(id, response);
+                Ok(())
+            }
+            Some(id) if self.abandoned.remove(&id) => Ok(()),
+            Some(id) if id < self.next_id => Err(ProtocolError::DuplicateResponse(id).into()),
+            None if self.last_batch.iter().any(|id| self.in_flight.contains(id)) => {
+                match response.error {
+                    Some(err) => {
+                        for id in self.last_batch.drain(..) {
+                            self.in_flight.remove(&id);
+                        }
+                        Err(ProtocolError::BatchUnsupported(err).into())
+                    }
+                    None => Err(ProtocolError::UnmatchedResponse(None).into()),
+                }
+            }
+            id => Err(ProtocolError::UnmatchedResponse(id).into()),
+        }
+    }
+
+    /// Gives up on `ids`, dropping their responses whether or not they've arrived yet.
+    fn abandon(&mut self, ids: &[RequestId]) {
+        for id in ids {
+            if self.in_flight.remove(id) {
+                self.abandoned.insert(*id);
+            }
+            self.responses.remove(id);
+        }
+    }
+
+    fn take_response<R: DeserializeOwned>(
+        &mut self,
+        id: RequestId,
+    ) -> Option<Result<R, RpcClientError>> {
+        self.responses.remove(&id).map(FullResponse::into_result)
+    }
+
+    /// Sends a request without waiting for its response.
+    pub fn start_request<P: Serialize>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<RequestId, RpcClientError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let req = FullRequest {
+            json_rpc: "2.0",
+            id,
+            method,
+            params,
+        };
+        self.conn.send(&req)?;
+        debug!("sent RPC request {} for {}", id, method);
+        self.in_flight.insert(id);
+        Ok(id)
+    }
+
+    /// Sends `requests`, as (method, params) pairs, in a single batch without waiting for responses.
+    /// Returns their ids in the same order, to wait on like any other request.
+    pub fn start_batch(
+        &mut self,
+        requests: &[(&str, Value)],
+    ) -> Result<Vec<RequestId>, RpcClientError> {
+        let first = self.next_id;
+        self.next_id += requests.len() as RequestId;
+        let ids: Vec<_> = (first..self.next_id).collect();
+        let batch: Vec<_> = requests
+            .iter()
+            .zip(&ids)
+            .map(|(&(method, ref params), &id)| FullRequest {
+                json_rpc: "2.0",
+                id,
+                method,
+                params,
+            })
+            .collect();
+        self.conn.send(&batch)?;
+        debug!("sent RPC batch with request ids {:?}", ids);
+        self.in_flight.extend(&ids);
+        self.last_batch = ids.clone();
+        Ok(ids)
+    }
+
+    /// Blocks until the response to `id` arrives, or the request times out.
+    /// Responses to other requests read in the meantime are kept for their callers.
+    pub fn wait_response<R: DeserializeOwned>(
+        &mut self,
+        id: RequestId,
+    ) -> Result<R, RpcClientError> {
+        let deadline = self.conn.timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(res) = self.take_response(id) {
+                return res;
+            }
+            if !self.in_flight.contains(&id) {
+                return Err(ProtocolError::NotPending(id).into());
+            }
+            let message = self.conn.recv(deadline)?;
+            self.handle_message(message)?;
+        }
+    }
+
+    /// Returns the response to `id` if it has already arrived.
+    pub fn poll_response<R: DeserializeOwned>(
+        &mut self,
+        id: RequestId,
+    ) -> Result<Option<R>, RpcClientError> {
+        loop {
+            match self.conn.incoming.try_recv() {
+                Ok(message) => self.handle_message(message)?,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+        match self.take_response(id) {
+            Some(res) => res.map(Some),
+            None if self.in_flight.contains(&id) => Ok(None),
+            None => Err(ProtocolError::NotPending(id).into()),
+        }
+    }
+
+    fn try_subscribe(&mut self) -> Result<bool, RpcClientError> {
+        let id = self.start_request("subscribe", TEMPLATE_EVENTS)?;
+        self.wait_response(id)
+    }
+
+    /// Asks the node to notify us of `TEMPLATE_EVENTS`, which then show up in `notifications`.
+    /// Returns false if the node doesn't support subscriptions.
+    pub fn subscribe(&mut self) -> bool {
+        if !self.conn.transport.supports_notifications() {
+            return false;
+        }
+        self.subscribed = match self.with_retry(|rpc| rpc.try_subscribe()) {
+            Ok(subscribed) => subscribed,
+            Err(err) => {
+                debug!("couldn't subscribe: {}", err);
+                false
+            }
+        };
+        self.subscribed
+    }
+
+    /// Switches nodes if the current one fell behind or a preferred one recovered,
+    /// according to the latest background health check.
+    pub fn check_health(&mut self) -> Result<(), RpcClientError> {
+        if let Some((i, reason)) = self.endpoints.choose() {
+            let order = self.endpoints.order_from(i);
+            let mut backoff = self.retry.start();
+            self.recover(order, reason, &mut backoff)?;
+        }
+        Ok(())
+    }
+
+    /// The address of the node we're currently connected to.
+    pub fn node(&self) -> &Endpoint {
+        self.endpoints.addr(self.endpoints.current())
+    }
+
+    /// How many times a node stopped answering without closing the connection.
+    pub fn stalls(&self) -> usize {
+        self.stalls
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribed
+    }
+
+    pub fn notifications(&self) -> Receiver<Notification> {
+        self.notifications.clone()
+    }
+
+    pub fn single_request<P: Serialize, R: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: P,
+    ) -> Result<R, RpcClientError> {
+        self.with_retry(|rpc| {
+            let id = rpc.start_request(method, &params)?;
+            rpc.wait_response(id)
+        })
+    }
+
+    pub fn get_height(&mut self) -> Result<usize, RpcClientError> {
+        self.single_request("merit_getHeight", [(); 0])
+    }
+
+    /// The hash of the block at `height`, counting from 0.
+    pub fn get_block_hash(&mut self, height: usize) -> Result<[u8; 32], RpcClientError> {
+        let block: RpcBlock = self.single_request("merit_getBlock", [height])?;
+        Ok(block.hash)
+    }
+
+    fn try_batch_poll(
+        &mut self,
+        miner_pubkey: &str,
+    ) -> Result<(usize, RpcMiningTarget), RpcClientError> {
+        let ids = self.start_batch(&[
+            ("merit_getHeight", Value::Array(Vec::new())),
+            ("merit_getBlockTemplate", Value::from(vec![miner_pubkey])),
+        ])?;
+        let polled = match self.wait_response(ids[0]) {
+            Ok(height) => self.wait_response(ids[1]).map(|target| (height, target)),
+            Err(err) => Err(err),
+        };
+        if polled.is_err() {
+            self.abandon(&ids);
+        }
+        polled
+    }
+
+    /// Gets the height and a block template together, in a single batch if the node supports it,
+    /// so they're less likely to disagree if a block arrives in between.
+    pub fn poll_template(
+        &mut self,
+        miner_pubkey: &str,
+    ) -> Result<(usize, RpcMiningTarget), RpcClientError> {
+        match self.supports_batches {
+            Some(true) => return self.with_retry(|rpc| rpc.try_batch_poll(miner_pubkey)),
+            Some(false) => {}
+            // Some nodes reject batches with an error, but others hang up or never answer,
+            // so any failure of the first one counts against them
+            None => match self.try_batch_poll(miner_pubkey) {
+                Ok(polled) => {
+                    self.supports_batches = Some(true);
+                    return Ok(polled);
+                }
+                Err(err) if err.should_reconnect() => {
+                    debug!("batch request failed: {}", err);
+                    info!("node doesn't support batch requests, falling back to sequential calls");
+                    self.supports_batches = Some(false);
+                }
+                Err(err) => return Err(err),
+            },
+        }
+        let height = self.get_height()?;
+        Ok((height, self.get_mining_target(miner_pubkey)?))
+    }
+
+    pub fn get_mining_target(
+        &mut self,
+        miner_pubkey: &str,
+    ) -> Result<RpcMiningTarget, RpcClientError> {
+        self.single_request("merit_getBlockTemplate", [miner_pubkey])
+    }
+}
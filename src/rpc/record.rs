@@ -0,0 +1,78 @@
+//! Recording everything sent to and received from nodes, as JSON lines.
+
+use super::Endpoint;
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A line of a recorded session.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the Unix epoch.
+    pub time: f64,
+    pub node: String,
+    pub direction: Direction,
+    pub message: Value,
+}
+
+/// Appends entries to a session file, shared between connections.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+    node: String,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            node: String::new(),
+        })
+    }
+
+    /// The same recorder, with entries attributed to `node`.
+    pub fn for_node(&self, node: &Endpoint) -> Recorder {
+        Recorder {
+            file: self.file.clone(),
+            node: node.to_string(),
+        }
+    }
+
+    pub fn record(&self, direction: Direction, message: &Value) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let entry = Entry {
+            time,
+            node: self.node.clone(),
+            direction,
+            message: message.clone(),
+        };
+        let mut file = self.file.lock();
+        // Flushed line by line, so nothing is lost if the miner dies right after
+        let res = serde_json::to_writer(&mut *file, &entry)
+            .map_err(io::Error::from)
+            .and_then(|()| file.write_all(b"\n"))
+            .and_then(|()| file.flush());
+        if let Err(err) = res {
+            warn!("failed to record RPC message: {}", err);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Answering requests from a session recorded with `--rpc-record`, instead of a node.
+//!
+//! Requests are matched to recorded ones by method, in order, rather than by when they're sent,
+//! so the miner's threads can interleave differently than they did while recording.
+//! Each recorded request's response is replayed with the new request's ids,
+//! followed by any notifications which arrived after it was sent.
+
+use super::{
+    record::{Direction, Entry},
+    Inbox, Notification, Transport,
+};
+use hashbrown::HashMap;
+use log::debug;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+struct Recorded {
+    request: Value,
+    response: Option<Value>,
+    notifications: Vec<Value>,
+}
+
+/// Recorded requests by method, or by comma separated methods for batches.
+type Session = HashMap<String, VecDeque<Recorded>>;
+
+/// The sessions a client is replaying, so reconnecting picks up where the last connection left off.
+#[derive(Default)]
+pub struct Sessions(Mutex<HashMap<PathBuf, Arc<Mutex<Session>>>>);
+
+/// The recording has no more requests for the method given, so there's nothing left to replay.
+#[derive(Debug)]
+pub struct Exhausted(pub String);
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no more recorded requests for {}", self.0)
+    }
+}
+
+impl std::error::Error for Exhausted {}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn method_key(request: &Value) -> String {
+    match request {
+        Value::Array(batch) => batch
+            .iter()
+            .map(|r| r["method"].as_str().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(","),
+        request => request["method"].as_str().unwrap_or_default().to_owned(),
+    }
+}
+
+fn ids(message: &Value) -> Vec<Option<u64>> {
+    match message {
+        Value::Array(batch) => batch.iter().map(|m| m["id"].as_u64()).collect(),
+        message => vec![message["id"].as_u64()],
+    }
+}
+
+fn load(path: &Path) -> io::Result<Session> {
+    let mut recorded: Vec<Recorded> = Vec::new();
+    let mut by_id = HashMap::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let entry: Entry = serde_json::from_str(&line?).map_err(|err| {
+            invalid_data(format!("line {} of {}: {}", i + 1, path.display(), err))
+        })?;
+        let message = entry.message;
+        match entry.direction {
+            Direction::Sent => {
+                for id in ids(&message).into_iter().flatten() {
+                    by_id.insert(id, recorded.len());
+                }
+                recorded.push(Recorded {
+                    request: message,
+                    response: None,
+                    notifications: Vec::new(),
+                });
+            }
+            Direction::Received if Notification::parse(&message).is_some() => {
+                if let Some(last) = recorded.last_mut() {
+                    last.notifications.push(message);
+                }
+            }
+            Direction::Received => {
+                let request = ids(&message)
+                    .into_iter()
+                    .flatten()
+                    .next()
+                    .and_then(|id| by_id.get(&id));
+                if let Some(&i) = request {
+                    recorded[i].response = Some(message);
+                }
+            }
+        }
+    }
+    let mut session = Session::new();
+    for r in recorded {
+        session
+            .entry(method_key(&r.request))
+            .or_default()
+            .push_back(r);
+    }
+    Ok(session)
+}
+
+pub struct ReplayTransport {
+    session: Arc<Mutex<Session>>,
+    inbox: Inbox,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &Path, sessions: &Sessions, inbox: Inbox) -> io::Result<ReplayTransport> {
+        let mut sessions = sessions.0.lock();
+        let session = match sessions.get(path) {
+            Some(session) => session.clone(),
+            None => {
+                let session = Arc::new(Mutex::new(load(path)?));
+                sessions.insert(path.to_owned(), session.clone());
+                session
+            }
+        };
+        Ok(ReplayTransport { session, inbox })
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let request: Value = serde_json::from_slice(message)?;
+        let key = method_key(&request);
+        let recorded = self
+            .session
+            .lock()
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, Exhausted(key.clone())))?;
+        if recorded.request["params"] != request["params"] {
+            debug!(
+                "replaying {} with params {} in place of recorded {}",
+                key, request["params"], recorded.request["params"],
+            );
+        }
+        if let Some(mut response) = recorded.response {
+            let id_map: HashMap<_, _> = ids(&recorded.request)
+                .into_iter()
+                .zip(ids(&request))
+                .collect();
+            let remap = |response: &mut Value| {
+                if let Some(&id) = id_map.get(&response["id"].as_u64()) {
+                    response["id"] = id.into();
+                }
+            };
+            match &mut response {
+                Value::Array(batch) => batch.iter_mut().for_each(remap),
+                response => remap(response),
+            }
+            self.inbox.deliver(Ok(response));
+        }
+        for notification in recorded.notifications {
+            self.inbox.deliver(Ok(notification));
+        }
+        Ok(())
+    }
+}
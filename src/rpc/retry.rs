@@ -0,0 +1,87 @@
+//! How long to keep retrying the node before giving up.
+
+use crate::cli::Opts;
+use rand::Rng;
+use std::{
+    cmp,
+    time::{Duration, Instant},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How many attempts to make in total, or `None` for no limit.
+    pub max_attempts: Option<u32>,
+    /// How long to keep retrying since the first attempt, or `None` for no limit.
+    pub max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn from_opts(opts: &Opts) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: INITIAL_BACKOFF,
+            max_backoff: Duration::from_secs(opts.rpc_retry_max_backoff),
+            max_attempts: opts.rpc_retry_max_attempts,
+            max_elapsed: match opts.rpc_retry_max_elapsed {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+    }
+
+    /// Starts counting attempts and elapsed time from now.
+    pub fn start(&self) -> Backoff {
+        Backoff {
+            policy: *self,
+            failures: 0,
+            started: Instant::now(),
+            next: self.initial_backoff,
+        }
+    }
+}
+
+pub struct Backoff {
+    policy: RetryPolicy,
+    failures: u32,
+    started: Instant,
+    next: Duration,
+}
+
+impl Backoff {
+    /// Call after a failed attempt.
+    /// Returns how long to wait before the next attempt, or `None` to give up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.failures += 1;
+        if let Some(max) = self.policy.max_attempts {
+            if self.failures >= max {
+                return None;
+            }
+        }
+        let backoff = self.next;
+        self.next = cmp::min(backoff * 2, self.policy.max_backoff);
+        // Somewhere between half and all of the backoff,
+        // so miners which lost the same node don't all come back at once.
+        let half = backoff / 2;
+        let delay = half + half.mul_f64(rand::thread_rng().gen::<f64>());
+        if let Some(max) = self.policy.max_elapsed {
+            if self.started.elapsed() + delay > max {
+                return None;
+            }
+        }
+        Some(delay)
+    }
+
+    /// Sleeps until the next attempt is due, or hands back `err` if it's time to give up.
+    pub fn wait<E>(&mut self, err: E) -> Result<(), E> {
+        match self.next_delay() {
+            Some(delay) => {
+                std::thread::sleep(delay);
+                Ok(())
+            }
+            None => Err(err),
+        }
+    }
+}
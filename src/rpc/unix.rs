@@ -0,0 +1,69 @@
+//! JSON streamed over a Unix domain socket, for nodes on the same host.
+
+use super::Stream;
+use log::warn;
+use parking_lot::{const_mutex, Mutex};
+use std::{
+    io,
+    net::Shutdown,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Sockets we've already warned about, so reconnects and health checks don't repeat it.
+static WARNED: Mutex<Vec<PathBuf>> = const_mutex(Vec::new());
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Connecting requires write permission on the socket, which the OS enforces for us.
+/// We just make sure the path is a socket and that it isn't open to other users.
+pub fn connect(path: &Path) -> io::Result<UnixStream> {
+    let metadata = std::fs::metadata(path)?;
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket", path.display()),
+        ));
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o022 != 0 {
+        let mut warned = WARNED.lock();
+        if !warned.iter().any(|p| p == path) {
+            warn!(
+                "RPC socket {} has mode {:o}, so other users can talk to the node too",
+                path.display(),
+                mode & 0o777,
+            );
+            warned.push(path.to_owned());
+        }
+    }
+    UnixStream::connect(path).map_err(|err| {
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "permission denied connecting to {}, check the socket's owner and mode",
+                    path.display(),
+                ),
+            )
+        } else {
+            err
+        }
+    })
+}
@@ -0,0 +1,132 @@
+//! Checking block templates from the node before mining on them.
+
+use crate::rpc::RpcMiningTarget;
+use std::{convert::TryInto, fmt};
+
+const MINER_KEY_SIZE: usize = 96;
+const MINER_NICK_SIZE: usize = 2;
+/// Everything up to the miner: version, last, contents, significant, sketch salt and sketch check.
+const MINER_FLAG_OFFSET: usize = 4 + 32 + 32 + 2 + 4 + 32;
+const TIME_SIZE: usize = 4;
+
+/// Why a block template can't be mined on.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidTemplate {
+    /// The header is cut short, or too long, for the miner it names.
+    Length { expected: usize, actual: usize },
+    /// The header names the miner with neither a public key nor a nickname.
+    MinerFlag(u8),
+    /// The header is for a new miner with a different public key than ours.
+    WrongMinerKey,
+    /// Any hash would pass, so the node would reject every block.
+    ZeroDifficulty,
+}
+
+impl fmt::Display for InvalidTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidTemplate::Length { expected, actual } => {
+                write!(f, "header is {} bytes long, expected {}", actual, expected)
+            }
+            InvalidTemplate::MinerFlag(flag) => write!(f, "header has invalid miner flag {}", flag),
+            InvalidTemplate::WrongMinerKey => {
+                write!(f, "header is for a different miner public key")
+            }
+            InvalidTemplate::ZeroDifficulty => write!(f, "difficulty is zero"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidTemplate {}
+
+/// How a block header names its miner.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderMiner<'a> {
+    /// A miner without merit yet, by BLS public key.
+    Key(&'a [u8]),
+    /// A miner with merit, by its nickname.
+    Nick(u16),
+}
+
+/// A Meros block header, as serialized for hashing, so without the proof and signature.
+#[derive(Debug)]
+pub struct BlockHeader<'a> {
+    pub version: u32,
+    pub last: &'a [u8],
+    pub contents: &'a [u8],
+    pub significant: u16,
+    pub sketch_salt: &'a [u8],
+    pub sketch_check: &'a [u8],
+    pub miner: HeaderMiner<'a>,
+    pub time: u32,
+}
+
+impl<'a> BlockHeader<'a> {
+    pub fn parse(header: &'a [u8]) -> Result<BlockHeader<'a>, InvalidTemplate> {
+        let length_error = |expected| InvalidTemplate::Length {
+            expected,
+            actual: header.len(),
+        };
+        let flag = *header
+            .get(MINER_FLAG_OFFSET)
+            .ok_or_else(|| length_error(MINER_FLAG_OFFSET + 1 + MINER_NICK_SIZE + TIME_SIZE))?;
+        let miner_size = match flag {
+            0 => MINER_NICK_SIZE,
+            1 => MINER_KEY_SIZE,
+            _ => return Err(InvalidTemplate::MinerFlag(flag)),
+        };
+        let expected = MINER_FLAG_OFFSET + 1 + miner_size + TIME_SIZE;
+        if header.len() != expected {
+            return Err(length_error(expected));
+        }
+        let (version, rest) = header.split_at(4);
+        let (last, rest) = rest.split_at(32);
+        let (contents, rest) = rest.split_at(32);
+        let (significant, rest) = rest.split_at(2);
+        let (sketch_salt, rest) = rest.split_at(4);
+        let (sketch_check, rest) = rest.split_at(32);
+        let (miner, time) = rest[1..].split_at(miner_size);
+        let miner = if flag == 1 {
+            HeaderMiner::Key(miner)
+        } else {
+            HeaderMiner::Nick(u16::from_be_bytes(miner.try_into().unwrap()))
+        };
+        Ok(BlockHeader {
+            version: u32::from_be_bytes(version.try_into().unwrap()),
+            last,
+            contents,
+            significant: u16::from_be_bytes(significant.try_into().unwrap()),
+            sketch_salt,
+            sketch_check,
+            miner,
+            time: u32::from_be_bytes(time.try_into().unwrap()),
+        })
+    }
+}
+
+/// A header of zeroes, but for a new miner with `miner_pubkey`.
+pub fn new_miner_header(miner_pubkey: &[u8]) -> Vec<u8> {
+    let mut header = vec![0; MINER_FLAG_OFFSET];
+    header.push(1);
+    header.extend(miner_pubkey);
+    header.extend(&[0; TIME_SIZE]);
+    header
+}
+
+/// Checks a template is something we can mine a valid block on, parsing its header.
+/// A nickname can't be checked against our key, so only new miners' keys are.
+pub fn validate<'a>(
+    target: &'a RpcMiningTarget,
+    miner_pubkey: &[u8],
+) -> Result<BlockHeader<'a>, InvalidTemplate> {
+    if target.difficulty == 0 {
+        return Err(InvalidTemplate::ZeroDifficulty);
+    }
+    let header = BlockHeader::parse(&target.header)?;
+    if let HeaderMiner::Key(key) = header.miner {
+        if key != miner_pubkey {
+            return Err(InvalidTemplate::WrongMinerKey);
+        }
+    }
+    Ok(header)
+}
@@ -0,0 +1,21 @@
+use crate::threads::balancer::rebalance;
+
+#[test]
+fn moves_threads_towards_the_bottleneck() {
+    assert_eq!(rebalance(2, 8, 180, 0, 200), 3);
+    assert_eq!(rebalance(4, 8, 0, 160, 200), 3);
+}
+
+#[test]
+fn only_moves_threads_for_clear_bottlenecks() {
+    assert_eq!(rebalance(2, 8, 150, 0, 200), 2);
+    assert_eq!(rebalance(2, 8, 100, 100, 200), 2);
+    assert_eq!(rebalance(2, 8, 0, 0, 200), 2);
+}
+
+#[test]
+fn keeps_a_thread_for_each_stage() {
+    assert_eq!(rebalance(7, 8, 200, 0, 200), 7);
+    assert_eq!(rebalance(1, 8, 0, 200, 200), 1);
+    assert_eq!(rebalance(1, 2, 200, 200, 200), 1);
+}
@@ -0,0 +1,33 @@
+use super::test_opts;
+use crate::threads::bench;
+use std::time::Duration;
+
+/// Light mode hashes slowly, so this is enough for a few batches of each stage.
+const LIGHT_MODE_DURATION: Duration = Duration::from_secs(2);
+
+#[test]
+fn measures_each_stage_and_sweeps_splits() {
+    let opts = test_opts(&["--randomx-light-mode", "--randomx-threads", "2"]);
+    let results = bench::run(&opts, LIGHT_MODE_DURATION, true).unwrap();
+    assert!(results.first_hashes > 0.0);
+    assert!(results.signatures > 0.0);
+    assert!(results.second_hashes > 0.0);
+    assert!(results.end_to_end > 0.0);
+    let splits: Vec<_> = results
+        .sweep
+        .iter()
+        .map(|s| (s.bls_threads, s.randomx_threads))
+        .collect();
+    assert_eq!(splits, vec![(1, 2), (2, 1)]);
+    assert!(results.sweep.iter().all(|s| s.end_to_end > 0.0));
+
+    let json = serde_json::to_value(&results).unwrap();
+    assert_eq!(json["randomx_threads"], 2);
+    assert_eq!(json["sweep"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn rejects_zero_threads() {
+    let opts = test_opts(&["--randomx-init-threads", "0"]);
+    assert!(bench::run(&opts, Duration::from_millis(500), false).is_err());
+}
@@ -0,0 +1,110 @@
+use super::{mock_node::MockNode, temp_path, test_opts};
+use crate::{
+    cli::{Command, JournalCommand},
+    commands,
+    journal::{self, Event, FoundBlock, Journal, Status},
+};
+
+fn found_block(template: i64, hash: u8) -> FoundBlock {
+    FoundBlock {
+        template_id: template,
+        header: vec![1, 2, 3],
+        nonce: 0x0102_0304,
+        signature: vec![9; 48],
+        hash: vec![hash; 32],
+        seq: 0,
+        height: 1,
+        time: journal::now(),
+    }
+}
+
+#[test]
+fn contents_are_header_nonce_and_signature() {
+    let contents = found_block(1, 0).contents();
+    assert_eq!(&contents[..7], &[1, 2, 3, 4, 3, 2, 1]);
+    assert_eq!(&contents[7..], &[9; 48][..]);
+}
+
+#[test]
+fn reads_latest_status_of_each_block() {
+    let path = temp_path("journal");
+    let mut journal = Journal::open(&path).unwrap();
+    let blocks: Vec<_> = (0..4).map(|i| found_block(1, i)).collect();
+    for block in &blocks {
+        journal.append(&Event::Found(block.clone())).unwrap();
+    }
+    journal
+        .append(&Event::Rejected {
+            hash: blocks[1].hash.clone(),
+            reason: "stale".to_owned(),
+        })
+        .unwrap();
+    journal
+        .append(&Event::Published {
+            hash: blocks[1].hash.clone(),
+        })
+        .unwrap();
+    journal
+        .append(&Event::Expired {
+            hash: blocks[2].hash.clone(),
+        })
+        .unwrap();
+    journal
+        .append(&Event::Rejected {
+            hash: blocks[3].hash.clone(),
+            reason: "stale".to_owned(),
+        })
+        .unwrap();
+    let entries = journal::read(&path).unwrap();
+    let blocks_read: Vec<_> = entries.iter().map(|e| e.block.clone()).collect();
+    assert_eq!(blocks_read, blocks);
+    let statuses: Vec<_> = entries.into_iter().map(|e| e.status).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            Status::Unpublished,
+            Status::Published,
+            Status::Expired,
+            Status::Rejected("stale".to_owned()),
+        ],
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn missing_journal_is_empty() {
+    assert!(journal::read(&temp_path("missing-journal"))
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn resubmits_unpublished_blocks() {
+    let node = MockNode::start();
+    let template = node.template().id;
+    let path = temp_path("journal");
+    let mut journal = Journal::open(&path).unwrap();
+    let unpublished = found_block(template, 1);
+    let published = found_block(template, 2);
+    journal.append(&Event::Found(unpublished.clone())).unwrap();
+    journal.append(&Event::Found(published.clone())).unwrap();
+    journal
+        .append(&Event::Published {
+            hash: published.hash.clone(),
+        })
+        .unwrap();
+
+    let opts = test_opts(&["--rpc", &node.addr(), "--journal", path.to_str().unwrap()]);
+    let command = Command::Journal(JournalCommand::Resubmit { hashes: Vec::new() });
+    commands::run(opts, command).unwrap();
+    let blocks = node.wait_for_published(std::time::Duration::from_secs(0), |_| true);
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].contents, unpublished.contents());
+    let statuses: Vec<_> = journal::read(&path)
+        .unwrap()
+        .into_iter()
+        .map(|e| e.status)
+        .collect();
+    assert_eq!(statuses, vec![Status::Published, Status::Published]);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,502 @@
+//! An in-process stand-in for a Meros node, speaking the same JSON-RPC as `Rpc`.
+
+use crate::bls::SecretKey;
+use hashbrown::HashMap;
+use parking_lot::{Condvar, Mutex};
+use serde_json::{json, Deserializer, Value};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub const MINER_KEY: [u8; 32] =
+    hex_literal::hex!("131f1303ca424d66ee051041322c0284b6a31f77916d204a875ecc42928f7501");
+
+/// The hash the node gives for the block at `height`.
+pub fn block_hash(height: usize) -> [u8; 32] {
+    let mut hash = [0xbb; 32];
+    hash[..8].copy_from_slice(&(height as u64).to_le_bytes());
+    hash
+}
+
+/// The public key the node puts in its templates unless told otherwise.
+pub fn miner_pubkey() -> Vec<u8> {
+    SecretKey::new(&MINER_KEY)
+        .unwrap()
+        .get_public_key()
+        .to_vec()
+}
+
+#[derive(Clone, Debug)]
+pub struct MockTemplate {
+    pub id: i64,
+    pub height: usize,
+    pub key: [u8; 32],
+    pub miner_pubkey: Vec<u8>,
+    pub header: Vec<u8>,
+    pub difficulty: u64,
+}
+
+impl MockTemplate {
+    pub fn new(
+        id: i64,
+        height: usize,
+        key: [u8; 32],
+        miner_pubkey: Vec<u8>,
+        difficulty: u64,
+    ) -> MockTemplate {
+        // A header for a new miner, with the height and id standing in for the last block hash
+        // and the contents, so every template is distinct.
+        let mut last = [0; 32];
+        last[..8].copy_from_slice(&(height as u64).to_le_bytes());
+        let mut contents = [0; 32];
+        contents[..8].copy_from_slice(&id.to_le_bytes());
+        let mut header = Vec::new();
+        header.extend(&0u32.to_be_bytes());
+        header.extend(&last);
+        header.extend(&contents);
+        header.extend(&[0; 2 + 4 + 32]);
+        header.push(1);
+        header.extend(&miner_pubkey);
+        header.extend(&(id as u32).to_be_bytes());
+        MockTemplate {
+            id,
+            height,
+            key,
+            miner_pubkey,
+            header,
+            difficulty,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PublishedBlock {
+    /// The template the block was published against, if its id was ever served.
+    pub template: Option<MockTemplate>,
+    pub contents: Vec<u8>,
+}
+
+type Conn = Arc<Mutex<Box<dyn Write + Send>>>;
+
+fn send(conn: &Conn, message: &Value) -> bool {
+    serde_json::to_writer(&mut *conn.lock(), message).is_ok()
+}
+
+struct State {
+    template: MockTemplate,
+    templates: HashMap<i64, MockTemplate>,
+    published: Vec<PublishedBlock>,
+    connections: usize,
+    /// When each template id was first handed out to the miner.
+    fetched: HashMap<i64, Instant>,
+    supports_subscriptions: bool,
+    subscribers: Vec<Conn>,
+    supports_batches: bool,
+    /// If stream connections are closed on batch requests instead of answering them.
+    hangs_up_on_batches: bool,
+    /// How many batch requests were received, whether or not they were supported.
+    batches: usize,
+    /// How many more publishes to answer with garbage instead of accepting.
+    garbled_publishes: usize,
+    /// The heights of the blocks asked for, in order.
+    block_requests: Vec<usize>,
+}
+
+impl State {
+    fn update<F: FnOnce(&mut MockTemplate)>(&mut self, f: F) {
+        let mut template = self.template.clone();
+        f(&mut template);
+        let template = MockTemplate::new(
+            self.template.id + 1,
+            template.height,
+            template.key,
+            template.miner_pubkey,
+            template.difficulty,
+        );
+        let event = if template.height == self.template.height {
+            "merit_template"
+        } else {
+            "merit_block"
+        };
+        self.templates.insert(template.id, template.clone());
+        self.template = template;
+        let notification = json!({ "jsonrpc": "2.0", "method": event, "params": [] });
+        self.subscribers.retain(|conn| send(conn, &notification));
+    }
+
+    /// `conn` is the connection to send notifications on, if the protocol allows for them.
+    fn respond(
+        &mut self,
+        conn: Option<&Conn>,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, (i64, &'static str)> {
+        match (method, conn) {
+            ("subscribe", Some(conn)) if self.supports_subscriptions => {
+                self.subscribers.push(conn.clone());
+                Ok(json!(true))
+            }
+            ("personal_getMiner", _) => Ok(json!(hex::encode_upper(MINER_KEY))),
+            ("merit_getHeight", _) => Ok(json!(self.template.height)),
+            ("merit_getBlock", _) => {
+                let height = params[0].as_u64().ok_or((-32602, "invalid block height"))? as usize;
+                if height >= self.template.height {
+                    return Err((-2, "block not found"));
+                }
+                self.block_requests.push(height);
+                Ok(json!({ "hash": hex::encode_upper(block_hash(height)) }))
+            }
+            ("merit_getBlockTemplate", _) => {
+                let now = Instant::now();
+                self.fetched.entry(self.template.id).or_insert(now);
+                Ok(json!({
+                "id": self.template.id,
+                "key": hex::encode_upper(self.template.key),
+                "header": hex::encode_upper(&self.template.header),
+                "difficulty": self.template.difficulty,
+                }))
+            }
+            ("merit_publishBlock", _) => {
+                let id = params[0].as_i64().ok_or((-32602, "invalid template id"))?;
+                let contents = params[1]
+                    .as_str()
+                    .and_then(|s| hex::decode(s).ok())
+                    .ok_or((-32602, "invalid block contents"))?;
+                self.published.push(PublishedBlock {
+                    template: self.templates.get(&id).cloned(),
+                    contents,
+                });
+                Ok(json!(true))
+            }
+            _ => Err((-32601, "method not found")),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Protocol {
+    Stream,
+    Http { token: Option<String> },
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever a template is fetched or a block is published.
+    changed: Condvar,
+}
+
+pub struct MockNode {
+    /// The TCP address or socket path the node listens on.
+    addr: String,
+    shared: Arc<Shared>,
+}
+
+impl MockNode {
+    /// Starts listening on an ephemeral localhost port, at height 1 with difficulty 1.
+    pub fn start() -> MockNode {
+        Self::start_with(true, Protocol::Stream)
+    }
+
+    /// Like `start`, but the node errors on `subscribe` like older nodes do.
+    pub fn start_without_subscriptions() -> MockNode {
+        Self::start_with(false, Protocol::Stream)
+    }
+
+    /// Like `start`, but speaking JSON-RPC over HTTP, optionally requiring a bearer token.
+    pub fn start_http(token: Option<&str>) -> MockNode {
+        let token = token.map(str::to_owned);
+        Self::start_with(false, Protocol::Http { token })
+    }
+
+    /// Like `start`, but listening on a Unix domain socket at `path`.
+    #[cfg(unix)]
+    pub fn start_unix(path: &std::path::Path) -> MockNode {
+        use std::os::unix::net::UnixListener;
+
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).expect("Failed to bind mock node");
+        let shared = Self::new_shared(true);
+        let shared2 = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                shared2.state.lock().connections += 1;
+                let shared = shared2.clone();
+                let writer = match stream.try_clone() {
+                    Ok(w) => w,
+                    Err(_) => continue,
+                };
+                std::thread::spawn(move || serve(shared, stream, Box::new(writer)));
+            }
+        });
+        MockNode {
+            addr: path.to_str().unwrap().to_owned(),
+            shared,
+        }
+    }
+
+    fn new_shared(supports_subscriptions: bool) -> Arc<Shared> {
+        let template = MockTemplate::new(1, 1, [1; 32], miner_pubkey(), 1);
+        let mut templates = HashMap::new();
+        templates.insert(template.id, template.clone());
+        Arc::new(Shared {
+            state: Mutex::new(State {
+                template,
+                templates,
+                published: Vec::new(),
+                connections: 0,
+                fetched: HashMap::new(),
+                supports_subscriptions,
+                subscribers: Vec::new(),
+                supports_batches: true,
+                hangs_up_on_batches: false,
+                batches: 0,
+                garbled_publishes: 0,
+                block_requests: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn start_with(supports_subscriptions: bool, protocol: Protocol) -> MockNode {
+        let shared = Self::new_shared(supports_subscriptions);
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind mock node");
+        let addr = listener.local_addr().unwrap().to_string();
+        let shared2 = shared.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => continue,
+                };
+                shared2.state.lock().connections += 1;
+                let shared = shared2.clone();
+                match protocol.clone() {
+                    Protocol::Stream => {
+                        let writer = match stream.try_clone() {
+                            Ok(w) => w,
+                            Err(_) => continue,
+                        };
+                        std::thread::spawn(move || serve(shared, stream, Box::new(writer)))
+                    }
+                    Protocol::Http { token } => {
+                        std::thread::spawn(move || serve_http(shared, stream, token))
+                    }
+                };
+            }
+        });
+        MockNode { addr, shared }
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.clone()
+    }
+
+    /// How many connections have been accepted in total.
+    pub fn connections(&self) -> usize {
+        self.shared.state.lock().connections
+    }
+
+    /// How many batch requests were received, whether or not they were supported.
+    pub fn batches(&self) -> usize {
+        self.shared.state.lock().batches
+    }
+
+    /// Makes the node reject batch requests like older nodes do.
+    pub fn disable_batches(&self) {
+        self.shared.state.lock().supports_batches = false;
+    }
+
+    /// Makes the node close stream connections on batch requests, as some proxies do.
+    pub fn hang_up_on_batches(&self) {
+        self.shared.state.lock().hangs_up_on_batches = true;
+    }
+
+    /// Answers the next `count` publishes with garbage, as if the connection broke
+    /// before the node got the block.
+    pub fn garble_publishes(&self, count: usize) {
+        self.shared.state.lock().garbled_publishes = count;
+    }
+
+    pub fn template(&self) -> MockTemplate {
+        self.shared.state.lock().template.clone()
+    }
+
+    pub fn set_height(&self, height: usize) {
+        self.shared.state.lock().update(|t| t.height = height);
+    }
+
+    pub fn set_key(&self, key: [u8; 32]) {
+        self.shared.state.lock().update(|t| t.key = key);
+    }
+
+    /// Puts `miner_pubkey` in the templates, standing in for a buggy or hostile node.
+    pub fn set_miner_pubkey(&self, miner_pubkey: Vec<u8>) {
+        self.shared
+            .state
+            .lock()
+            .update(|t| t.miner_pubkey = miner_pubkey);
+    }
+
+    pub fn set_difficulty(&self, difficulty: u64) {
+        self.shared
+            .state
+            .lock()
+            .update(|t| t.difficulty = difficulty);
+    }
+
+    fn wait<F: FnMut(&State) -> bool>(&self, timeout: Duration, mut done: F) {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock();
+        while !done(&state) {
+            if self
+                .shared
+                .changed
+                .wait_until(&mut state, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Waits until `done` returns true for the published blocks, or until `timeout` passes.
+    /// Returns every block published so far either way.
+    pub fn wait_for_published<F>(&self, timeout: Duration, mut done: F) -> Vec<PublishedBlock>
+    where
+        F: FnMut(&[PublishedBlock]) -> bool,
+    {
+        self.wait(timeout, |state| done(&state.published));
+        self.shared.state.lock().published.clone()
+    }
+
+    /// Waits until the block at `height` is asked for, returning if it was.
+    pub fn wait_for_block_request(&self, height: usize, timeout: Duration) -> bool {
+        self.wait(timeout, |state| state.block_requests.contains(&height));
+        self.shared.state.lock().block_requests.contains(&height)
+    }
+
+    /// Waits until the template `id` is fetched, returning when it first was.
+    pub fn wait_for_fetch(&self, id: i64, timeout: Duration) -> Option<Instant> {
+        self.wait(timeout, |state| state.fetched.contains_key(&id));
+        self.shared.state.lock().fetched.get(&id).cloned()
+    }
+}
+
+fn handle(shared: &Shared, conn: Option<&Conn>, req: &Value) -> Value {
+    if let Value::Array(batch) = req {
+        let supported = {
+            let mut state = shared.state.lock();
+            state.batches += 1;
+            state.supports_batches
+        };
+        if !supported {
+            return json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": -32600, "message": "invalid request" },
+            });
+        }
+        return Value::Array(batch.iter().map(|req| handle(shared, conn, req)).collect());
+    }
+    let method = req["method"].as_str().unwrap_or_default();
+    let res = shared.state.lock().respond(conn, method, &req["params"]);
+    shared.changed.notify_all();
+    match res {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": req["id"], "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": req["id"],
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+fn serve<R: Read>(shared: Arc<Shared>, reader: R, writer: Box<dyn Write + Send>) {
+    let conn = Arc::new(Mutex::new(writer));
+    for req in Deserializer::from_reader(reader).into_iter::<Value>() {
+        let req = match req {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+        if req["method"] == "merit_publishBlock" {
+            let mut state = shared.state.lock();
+            if state.garbled_publishes > 0 {
+                state.garbled_publishes -= 1;
+                let _ = conn.lock().write_all(b"not json");
+                return;
+            }
+        } else if req.is_array() {
+            let mut state = shared.state.lock();
+            if state.hangs_up_on_batches {
+                state.batches += 1;
+                return;
+            }
+        }
+        let res = handle(&shared, Some(&conn), &req);
+        if !send(&conn, &res) {
+            return;
+        }
+    }
+}
+
+/// Serves keep-alive HTTP/1.1 requests until the client hangs up.
+fn serve_http(shared: Arc<Shared>, stream: TcpStream, token: Option<String>) {
+    let mut writer = match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let mut reader = BufReader::new(stream);
+    let expected_auth = token.map(|t| format!("Bearer {}", t));
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut content_length = 0;
+        let mut auth = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let value = parts.next().unwrap_or_default().trim();
+            match name.as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "authorization" => auth = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        let (status, body) = if expected_auth.is_some() && auth != expected_auth {
+            ("401 Unauthorized", Vec::new())
+        } else {
+            match serde_json::from_slice(&body) {
+                Ok(req) => (
+                    "200 OK",
+                    serde_json::to_vec(&handle(&shared, None, &req)).unwrap(),
+                ),
+                Err(_) => ("400 Bad Request", Vec::new()),
+            }
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            status,
+            body.len(),
+        );
+        if writer.write_all(head.as_bytes()).is_err() || writer.write_all(&body).is_err() {
+            return;
+        }
+    }
+}
@@ -1 +1,57 @@
+use crate::cli::Opts;
+use randomx::{Cache, Flags, Vm};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use structopt::StructOpt;
+
+mod balancer;
+mod bench;
 mod difficulty;
+mod journal;
+mod mock_node;
+mod nonces;
+mod pause;
+mod pipeline;
+mod placement;
+mod rpc;
+mod supervisor;
+mod template;
+mod verify;
+
+/// A path in the temporary directory no other test uses.
+fn temp_path(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("meroxidizer-{}-{}-{}", name, std::process::id(), n))
+}
+
+/// `args`, plus minimal thread counts and a fresh journal unless they say otherwise.
+fn test_opts(args: &[&str]) -> Opts {
+    let mut all = vec!["meroxidizer"];
+    all.extend(args);
+    let journal = temp_path("journal");
+    let defaults = [
+        ("--randomx-init-threads", "1"),
+        ("--randomx-threads", "1"),
+        ("--bls-threads", "1"),
+        ("--journal", journal.to_str().unwrap()),
+    ];
+    for &(flag, value) in &defaults {
+        if !args.contains(&flag) {
+            all.extend(&[flag, value]);
+        }
+    }
+    Opts::from_iter(all)
+}
+
+/// A RandomX VM for `key` in light mode, which is quick to set up but slow to hash with.
+fn light_vm(key: &[u8; 32]) -> Vm {
+    let mut flags = Flags::recommended();
+    flags.set_full_mem(false);
+    Vm::new(Arc::new(Cache::new(flags, key, 1).unwrap())).unwrap()
+}
@@ -0,0 +1,27 @@
+use crate::threads::nonces::{Nonces, BATCHES};
+
+#[test]
+fn claims_batches_in_order() {
+    let nonces = Nonces::new(100);
+    assert_eq!(nonces.claim(), Some(100));
+    assert_eq!(nonces.claim(), Some(164));
+    assert_eq!(nonces.claim(), Some(228));
+    assert!(!nonces.exhausted());
+}
+
+#[test]
+fn wraps_around_and_runs_out() {
+    let start = u32::MAX - 63;
+    let nonces = Nonces::new(start);
+    assert_eq!(nonces.claim(), Some(start));
+    assert_eq!(nonces.claim(), Some(0));
+    let mut last = None;
+    for _ in 2..BATCHES {
+        last = nonces.claim();
+    }
+    // The last batch ends right before the first
+    assert_eq!(last, Some(start - 64));
+    assert!(nonces.exhausted());
+    assert_eq!(nonces.claim(), None);
+    assert_eq!(nonces.claim(), None);
+}
@@ -0,0 +1,103 @@
+use crate::threads::pause::{Pause, Worker};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+/// Stands in for the template and cache the hashers hold on to.
+type Shared = Arc<RwLock<Arc<u32>>>;
+
+/// Lets go of what it holds and parks, if a pause was requested.
+fn checkpoint(worker: &Worker, shared: &Shared, held: &mut Arc<u32>) {
+    if worker.requested() {
+        *held = Arc::new(0);
+        worker.park();
+        *held = shared.read().clone();
+    }
+}
+
+/// Sends what it holds until `output` fills up, like RandomX threads with first hashes.
+fn run_sender(worker: Worker, shared: Shared, output: Sender<u32>) {
+    let mut held = shared.read().clone();
+    loop {
+        select! {
+            send(output, *held) -> res => if res.is_err() {
+                return;
+            },
+            recv(worker.wake()) -> _ => {},
+        }
+        checkpoint(&worker, &shared, &mut held);
+    }
+}
+
+/// Waits on `input` forever, like RandomX threads with no second hashes coming in.
+fn run_receiver(worker: Worker, shared: Shared, input: Receiver<()>) {
+    let mut held = shared.read().clone();
+    loop {
+        select! {
+            recv(input) -> res => if res.is_err() {
+                return;
+            },
+            recv(worker.wake()) -> _ => {},
+        }
+        checkpoint(&worker, &shared, &mut held);
+    }
+}
+
+/// Changes the shared value in place, which only works if no worker holds it.
+fn rekey(pause: &Pause, shared: &Shared, value: u32) {
+    let paused = pause.pause();
+    let mut lock = shared.write();
+    *Arc::get_mut(&mut lock).expect("a worker still holds the shared value") = value;
+    drop(lock);
+    drop(paused);
+}
+
+#[test]
+fn parks_blocked_workers_and_releases_them() {
+    let pause = Arc::new(Pause::new());
+    let shared = Arc::new(RwLock::new(Arc::new(1)));
+    let (output, outputs) = bounded(1);
+    let (input_send, input) = bounded(1);
+    let threads = vec![
+        {
+            let (worker, shared) = (pause.register(), shared.clone());
+            std::thread::spawn(move || run_sender(worker, shared, output))
+        },
+        {
+            let (worker, shared) = (pause.register(), shared.clone());
+            std::thread::spawn(move || run_receiver(worker, shared, input))
+        },
+    ];
+    // Now the sender is blocked on a full channel, and the receiver on an empty one
+    assert_eq!(outputs.recv().unwrap(), 1);
+    for value in 2..5 {
+        rekey(&pause, &shared, value);
+        // Anything sent before the pause is still the old value, and then only the new one
+        let mut received = outputs.recv().unwrap();
+        while received != value {
+            assert_eq!(received, value - 1);
+            received = outputs.recv().unwrap();
+        }
+        assert_eq!(outputs.recv().unwrap(), value);
+    }
+    drop(outputs);
+    drop(input_send);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn exited_workers_dont_hold_up_a_pause() {
+    let pause = Arc::new(Pause::new());
+    let shared = Arc::new(RwLock::new(Arc::new(1)));
+    let (input_send, input) = bounded(1);
+    let thread = {
+        let (worker, shared) = (pause.register(), shared.clone());
+        std::thread::spawn(move || run_receiver(worker, shared, input))
+    };
+    drop(input_send);
+    thread.join().unwrap();
+    rekey(&pause, &shared, 2);
+    assert_eq!(**shared.read(), 2);
+}
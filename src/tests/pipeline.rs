@@ -0,0 +1,375 @@
+use super::{
+    light_vm,
+    mock_node::{block_hash, miner_pubkey, MockNode, PublishedBlock},
+    temp_path, test_opts,
+};
+use crate::{
+    bls::{PublicKey, SIG_SIZE},
+    journal,
+    rpc::RpcClientError,
+    threads::{self, Miner, ShutdownError},
+    verify::verify,
+};
+use crossbeam_channel::RecvTimeoutError;
+use hashbrown::HashMap;
+use randomx::Vm;
+use std::time::{Duration, Instant};
+
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(120);
+/// Well under the interval the miner polls at when subscribed,
+/// so a fetch this fast must have been prompted by a notification.
+const PROMPT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Mining stops once the returned miner is dropped.
+fn start_miner_with(node: &MockNode, args: &[&str]) -> Miner {
+    let addr = node.addr();
+    let mut all = vec!["--rpc", &addr, "--randomx-light-mode"];
+    all.extend(args);
+    threads::start(test_opts(&all)).unwrap()
+}
+
+fn start_miner(node: &MockNode) -> Miner {
+    start_miner_with(node, &[])
+}
+
+/// Recomputes published blocks the way a node would, in RandomX light mode.
+struct Verifier {
+    miner_key: PublicKey,
+    vms: HashMap<[u8; 32], Vm>,
+}
+
+impl Verifier {
+    fn new() -> Verifier {
+        Verifier {
+            miner_key: PublicKey::new(&miner_pubkey()).unwrap(),
+            vms: HashMap::new(),
+        }
+    }
+
+    fn assert_valid(&mut self, block: &PublishedBlock) {
+        let template = block
+            .template
+            .as_ref()
+            .expect("block published against unknown template");
+        let header_len = template.header.len();
+        assert_eq!(block.contents.len(), header_len + 4 + SIG_SIZE);
+        assert_eq!(&block.contents[..header_len], &template.header[..]);
+        let vm = self
+            .vms
+            .entry(template.key)
+            .or_insert_with(|| light_vm(&template.key));
+        let mut nonce = [0; 4];
+        nonce.copy_from_slice(&block.contents[header_len..(header_len + 4)]);
+        let mut sig = [0; SIG_SIZE];
+        sig.copy_from_slice(&block.contents[(header_len + 4)..]);
+        let verification = verify(
+            vm,
+            &template.header,
+            u32::from_le_bytes(nonce),
+            &sig,
+            Some(&self.miner_key),
+            Some(template.difficulty),
+        );
+        assert_eq!(verification.signature_valid, Some(true));
+        assert_eq!(
+            verification.meets_difficulty,
+            Some(true),
+            "block hash {} above max hash {}",
+            hex::encode(verification.second_hash),
+            hex::encode(verification.max_hash.unwrap()),
+        );
+    }
+}
+
+/// Mines with `args`, and checks the first couple of blocks published.
+fn assert_publishes_valid_blocks(args: &[&str]) {
+    let node = MockNode::start();
+    let _miner = start_miner_with(&node, args);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| p.len() >= 2);
+    assert!(
+        published.len() >= 2,
+        "only {} blocks published",
+        published.len()
+    );
+    let mut verifier = Verifier::new();
+    for block in &published {
+        verifier.assert_valid(block);
+    }
+}
+
+#[test]
+fn publishes_valid_blocks() {
+    assert_publishes_valid_blocks(&[]);
+}
+
+#[test]
+fn mines_with_any_number_of_randomx_threads() {
+    assert_publishes_valid_blocks(&["--randomx-threads", "3"]);
+}
+
+#[test]
+fn mines_with_auto_balance() {
+    assert_publishes_valid_blocks(&[
+        "--auto-balance",
+        "--bls-threads",
+        "2",
+        "--randomx-threads",
+        "2",
+    ]);
+}
+
+#[test]
+fn rejects_zero_threads() {
+    assert!(threads::start(test_opts(&["--bls-threads", "0"])).is_err());
+    assert!(threads::start(test_opts(&["--randomx-init-threads", "0"])).is_err());
+}
+
+#[test]
+fn mines_in_light_mode_without_init_threads() {
+    let node = MockNode::start();
+    let _miner = start_miner_with(&node, &["--randomx-init-threads", "0"]);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+    Verifier::new().assert_valid(&published[0]);
+}
+
+#[test]
+fn mines_with_pinned_threads() {
+    let node = MockNode::start();
+    let core = core_affinity::get_core_ids().unwrap()[0].id.to_string();
+    let _miner = start_miner_with(&node, &["--randomx-cores", &core, "--bls-cores", &core]);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+    Verifier::new().assert_valid(&published[0]);
+}
+
+#[test]
+fn finds_the_same_blocks_with_a_fixed_nonce_seed() {
+    let first_block = || {
+        let node = MockNode::start();
+        let _miner = start_miner_with(&node, &["--nonce-seed", "7"]);
+        let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+        assert!(!published.is_empty(), "no blocks published");
+        published[0].contents.clone()
+    };
+    assert_eq!(first_block(), first_block());
+}
+
+#[test]
+fn retries_publishing_after_connection_failure() {
+    let node = MockNode::start();
+    node.garble_publishes(1);
+    let path = temp_path("journal");
+    let _miner = start_miner_with(&node, &["--journal", path.to_str().unwrap()]);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+    assert!(node.connections() >= 2, "never reconnected");
+    Verifier::new().assert_valid(&published[0]);
+    // The block the node never got is the first one found, and it went out again
+    let entries = journal::read(&path).unwrap();
+    assert_eq!(entries[0].block.contents(), published[0].contents);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn publishes_found_blocks_on_shutdown() {
+    let node = MockNode::start();
+    let path = temp_path("journal");
+    let miner = start_miner_with(&node, &["--journal", path.to_str().unwrap()]);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+    miner.shutdown(Instant::now() + SHUTDOWN_TIMEOUT).unwrap();
+    // Nothing found is left behind, and nothing is found once the miner's stopped
+    let entries = journal::read(&path).unwrap();
+    for entry in &entries {
+        assert_eq!(entry.status, journal::Status::Published);
+    }
+    let published = node.wait_for_published(Duration::from_secs(0), |_| true);
+    assert_eq!(published.len(), entries.len());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn mines_from_a_recorded_session_until_it_runs_out() {
+    // Nothing gets found at this difficulty, so the session is only template polls
+    let node = MockNode::start_without_subscriptions();
+    node.set_difficulty(u64::MAX);
+    let session = temp_path("session");
+    let miner = start_miner_with(&node, &["--rpc-record", session.to_str().unwrap()]);
+    node.set_height(2);
+    let last = node.template();
+    assert!(
+        node.wait_for_fetch(last.id, PUBLISH_TIMEOUT).is_some(),
+        "miner never fetched the new template",
+    );
+    miner.shutdown(Instant::now() + SHUTDOWN_TIMEOUT).unwrap();
+
+    let replay = format!("replay://{}", session.display());
+    let miner = threads::start(test_opts(&["--rpc", &replay, "--randomx-light-mode"])).unwrap();
+    // Without the recorded node to retry, the miner gives up once the session runs out
+    assert_eq!(
+        miner.rpc_exited().recv_timeout(PUBLISH_TIMEOUT),
+        Err(RecvTimeoutError::Disconnected),
+    );
+    match miner.shutdown(Instant::now() + SHUTDOWN_TIMEOUT) {
+        Err(ShutdownError::Rpc(RpcClientError::ReplayExhausted(_))) => {}
+        res => panic!("unexpected shutdown: {:?}", res),
+    }
+    let _ = std::fs::remove_file(&session);
+}
+
+fn assert_follows_height_and_key_changes(args: &[&str]) {
+    let node = MockNode::start();
+    let _miner = start_miner_with(&node, args);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+
+    let old_template = node.template();
+    node.set_height(old_template.height + 1);
+    node.set_key([2; 32]);
+    node.set_difficulty(2);
+    let new_template = node.template();
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| {
+        p.iter()
+            .any(|b| b.template.as_ref().map(|t| t.id) == Some(new_template.id))
+    });
+    assert!(
+        published
+            .iter()
+            .any(|b| b.template.as_ref().map(|t| t.id) == Some(new_template.id)),
+        "no blocks published for the new template",
+    );
+    let mut verifier = Verifier::new();
+    for block in &published {
+        verifier.assert_valid(block);
+    }
+}
+
+#[test]
+fn follows_height_and_key_changes() {
+    assert_follows_height_and_key_changes(&[]);
+}
+
+#[test]
+fn pauses_mining_to_rekey_in_place() {
+    assert_follows_height_and_key_changes(&["--randomx-stop-for-rekey"]);
+}
+
+#[test]
+fn never_mixes_keys_within_a_block() {
+    let node = MockNode::start();
+    // Hard enough that a block hashed partly with the wrong key would likely fail
+    node.set_difficulty(4);
+    let _miner = start_miner(&node);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+
+    // Without a height change, only the key tells batches from before and after apart
+    node.set_key([2; 32]);
+    let new_template = node.template();
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| {
+        p.iter()
+            .filter(|b| b.template.as_ref().map(|t| t.id) == Some(new_template.id))
+            .count()
+            >= 4
+    });
+    let mut verifier = Verifier::new();
+    for block in &published {
+        verifier.assert_valid(block);
+    }
+}
+
+#[test]
+fn prepares_upcoming_key() {
+    let node = MockNode::start();
+    let _miner = start_miner(&node);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+
+    // Into the next epoch, but still before the key switch
+    node.set_height(385);
+    assert!(
+        node.wait_for_block_request(383, PROMPT_FETCH_TIMEOUT),
+        "miner never asked for the block seeding the next key",
+    );
+    node.set_height(396);
+    node.set_key(block_hash(383));
+    let new_template = node.template();
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| {
+        p.iter()
+            .any(|b| b.template.as_ref().map(|t| t.id) == Some(new_template.id))
+    });
+    let new_blocks: Vec<_> = published
+        .iter()
+        .filter(|b| b.template.as_ref().map(|t| t.id) == Some(new_template.id))
+        .collect();
+    assert!(
+        !new_blocks.is_empty(),
+        "no blocks published with the new key"
+    );
+    let mut verifier = Verifier::new();
+    for block in new_blocks {
+        verifier.assert_valid(block);
+    }
+}
+
+#[test]
+fn skips_invalid_templates() {
+    let node = MockNode::start();
+    let _miner = start_miner(&node);
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| !p.is_empty());
+    assert!(!published.is_empty(), "no blocks published");
+
+    node.set_miner_pubkey(vec![7; 96]);
+    let bad = node.template();
+    assert!(
+        node.wait_for_fetch(bad.id, PROMPT_FETCH_TIMEOUT).is_some(),
+        "miner never fetched the invalid template",
+    );
+    node.set_miner_pubkey(miner_pubkey());
+    let good = node.template();
+    let published = node.wait_for_published(PUBLISH_TIMEOUT, |p| {
+        p.iter()
+            .any(|b| b.template.as_ref().map(|t| t.id) == Some(good.id))
+    });
+    assert!(
+        published
+            .iter()
+            .any(|b| b.template.as_ref().map(|t| t.id) == Some(good.id)),
+        "no blocks published after the node recovered",
+    );
+    assert!(
+        published
+            .iter()
+            .all(|b| b.template.as_ref().map(|t| t.id) != Some(bad.id)),
+        "mined on the invalid template",
+    );
+}
+
+fn assert_refreshes_promptly(node: &MockNode) {
+    // Nothing gets found at this difficulty, so publishing never prompts a refresh
+    node.set_difficulty(u64::MAX);
+    let first = node.template();
+    let _miner = start_miner(node);
+    assert!(
+        node.wait_for_fetch(first.id, PUBLISH_TIMEOUT).is_some(),
+        "miner never fetched a template",
+    );
+    node.set_height(first.height + 1);
+    let next = node.template();
+    assert!(
+        node.wait_for_fetch(next.id, PROMPT_FETCH_TIMEOUT).is_some(),
+        "miner didn't fetch the new template promptly",
+    );
+}
+
+#[test]
+fn refreshes_template_on_notification() {
+    assert_refreshes_promptly(&MockNode::start());
+}
+
+#[test]
+fn polls_without_subscriptions() {
+    assert_refreshes_promptly(&MockNode::start_without_subscriptions());
+}
@@ -0,0 +1,18 @@
+use crate::placement::CoreList;
+
+#[test]
+fn parses_core_lists() {
+    let cores: CoreList = "0-3,8,10-11".parse().unwrap();
+    assert_eq!(cores, CoreList(vec![0, 1, 2, 3, 8, 10, 11]));
+    assert_eq!(cores.to_string(), "0-3,8,10-11");
+    // As Linux lists the cores of NUMA nodes
+    assert_eq!("5\n".parse(), Ok(CoreList(vec![5])));
+}
+
+#[test]
+fn rejects_invalid_core_lists() {
+    assert!("".parse::<CoreList>().is_err());
+    assert!("1,,2".parse::<CoreList>().is_err());
+    assert!("3-1".parse::<CoreList>().is_err());
+    assert!("a-b".parse::<CoreList>().is_err());
+}
@@ -0,0 +1,527 @@
+use super::{mock_node::MockNode, test_opts};
+use crate::rpc::{Endpoint, ProtocolError, Rpc, RpcClientError};
+use serde_json::{json, Deserializer, Value};
+use std::{
+    io::{BufRead, Read, Write},
+    net::{TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+/// Accepts one connection, reads `num_requests` requests, and hands them to `respond`.
+fn scripted_server<F>(num_requests: usize, respond: F) -> Rpc
+where
+    F: FnOnce(&[Value], &mut TcpStream) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let requests: Vec<Value> = Deserializer::from_reader(stream.try_clone().unwrap())
+            .into_iter()
+            .take(num_requests)
+            .map(Result::unwrap)
+            .collect();
+        respond(&requests, &mut stream);
+        // Keep the connection open until the client is done with it
+        let _ = Deserializer::from_reader(stream)
+            .into_iter::<Value>()
+            .count();
+    });
+    Rpc::connect(test_opts(&["--rpc", &addr])).unwrap()
+}
+
+fn respond(stream: &mut TcpStream, id: &Value, result: Value) {
+    let res = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+    serde_json::to_writer(stream, &res).unwrap();
+}
+
+#[test]
+fn request_ids_are_unique() {
+    let mut rpc = scripted_server(2, |reqs, stream| {
+        assert_ne!(reqs[0]["id"], reqs[1]["id"]);
+        respond(stream, &reqs[0]["id"], json!(1));
+        respond(stream, &reqs[1]["id"], json!(2));
+    });
+    let first = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    let second = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    assert_eq!(rpc.wait_response::<u64>(first).unwrap(), 1);
+    assert_eq!(rpc.wait_response::<u64>(second).unwrap(), 2);
+}
+
+#[test]
+fn matches_out_of_order_responses() {
+    let mut rpc = scripted_server(2, |reqs, stream| {
+        respond(stream, &reqs[1]["id"], json!("second"));
+        respond(stream, &reqs[0]["id"], json!(true));
+    });
+    let publish = rpc.start_request("merit_publishBlock", (0, "")).unwrap();
+    let height = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    assert_eq!(rpc.wait_response::<String>(height).unwrap(), "second");
+    // Sent after the other response, so it may not have been read yet
+    assert!(rpc.wait_response::<bool>(publish).unwrap());
+}
+
+#[test]
+fn polls_for_pending_responses() {
+    let (reply, replying) = crossbeam_channel::bounded(0);
+    let mut rpc = scripted_server(1, move |reqs, stream| {
+        replying.recv().unwrap();
+        respond(stream, &reqs[0]["id"], json!(1));
+    });
+    let id = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    assert_eq!(rpc.poll_response::<u64>(id).unwrap(), None);
+    reply.send(()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(height) = rpc.poll_response::<u64>(id).unwrap() {
+            assert_eq!(height, 1);
+            break;
+        }
+        assert!(Instant::now() < deadline, "response never arrived");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn rejects_unmatched_response() {
+    let mut rpc = scripted_server(1, |_, stream| {
+        respond(stream, &json!(12345), json!(1));
+    });
+    let id = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    match rpc.wait_response::<u64>(id) {
+        Err(RpcClientError::Protocol(ProtocolError::UnmatchedResponse(Some(12345)))) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn rejects_duplicate_response() {
+    let mut rpc = scripted_server(1, |reqs, stream| {
+        respond(stream, &reqs[0]["id"], json!(1));
+        respond(stream, &reqs[0]["id"], json!(1));
+    });
+    let id = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    assert_eq!(rpc.wait_response::<u64>(id).unwrap(), 1);
+    let next = rpc.start_request("merit_getHeight", [(); 0]).unwrap();
+    match rpc.wait_response::<u64>(next) {
+        Err(RpcClientError::Protocol(ProtocolError::DuplicateResponse(dup))) if dup == id => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn gives_up_connecting_after_max_attempts() {
+    // Nothing listens on a port once its listener is dropped
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let started = Instant::now();
+    let res = Rpc::connect(test_opts(&[
+        "--rpc",
+        &addr,
+        "--rpc-retry-max-attempts",
+        "3",
+    ]));
+    match res {
+        Err(RpcClientError::Connection(_)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("connected to {}", addr),
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn returns_rpc_errors_without_retrying() {
+    let mut rpc = scripted_server(1, |reqs, stream| {
+        let res = json!({
+            "jsonrpc": "2.0",
+            "id": reqs[0]["id"],
+            "error": { "code": -32601, "message": "method not found" },
+        });
+        serde_json::to_writer(stream, &res).unwrap();
+    });
+    // The scripted server only ever answers one request, so a retry would hang
+    match rpc.single_request::<_, String>("personal_getMiner", [(); 0]) {
+        Err(RpcClientError::Rpc(err)) => assert_eq!(err.code, -32601),
+        res => panic!("unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn reconnects_after_protocol_error() {
+    let node = MockNode::start();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let bad_addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.write_all(b"not json");
+    });
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &bad_addr, "--rpc", &node.addr()])).unwrap();
+    assert_eq!(rpc.get_height().unwrap(), 1);
+    assert_eq!(rpc.node(), &Endpoint::Tcp(node.addr()));
+}
+
+#[test]
+fn reconnects_to_another_node_after_stall() {
+    let node = MockNode::start();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stalled_addr = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        // Read requests forever, but never answer them
+        let (stream, _) = listener.accept().unwrap();
+        let _ = Deserializer::from_reader(stream)
+            .into_iter::<Value>()
+            .count();
+    });
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &stalled_addr,
+        "--rpc",
+        &node.addr(),
+        "--rpc-timeout",
+        "1",
+    ]))
+    .unwrap();
+    assert_eq!(rpc.node(), &Endpoint::Tcp(stalled_addr));
+    let started = Instant::now();
+    assert_eq!(rpc.get_height().unwrap(), 1);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(rpc.node(), &Endpoint::Tcp(node.addr()));
+    assert_eq!(rpc.stalls(), 1);
+}
+
+#[test]
+fn polls_height_and_template_in_one_batch() {
+    let node = MockNode::start();
+    node.set_height(3);
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &node.addr()])).unwrap();
+    let (height, target) = rpc.poll_template("00").unwrap();
+    assert_eq!(height, 3);
+    assert_eq!(target.id, node.template().id);
+    assert_eq!(node.batches(), 1);
+}
+
+#[test]
+fn falls_back_without_batch_support() {
+    let node = MockNode::start();
+    node.disable_batches();
+    node.set_height(3);
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &node.addr()])).unwrap();
+    for _ in 0..2 {
+        let (height, target) = rpc.poll_template("00").unwrap();
+        assert_eq!(height, 3);
+        assert_eq!(target.id, node.template().id);
+    }
+    // Only tried once, on the same connection
+    assert_eq!(node.batches(), 1);
+    assert_eq!(node.connections(), 1);
+}
+
+#[test]
+fn falls_back_when_the_node_hangs_up_on_batches() {
+    let node = MockNode::start();
+    node.hang_up_on_batches();
+    node.set_height(3);
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &node.addr()])).unwrap();
+    for _ in 0..2 {
+        let (height, target) = rpc.poll_template("00").unwrap();
+        assert_eq!(height, 3);
+        assert_eq!(target.id, node.template().id);
+    }
+    // Reconnecting to the same node doesn't make us try again
+    assert_eq!(node.batches(), 1);
+    assert_eq!(node.connections(), 2);
+}
+
+fn wait_for_node(rpc: &mut Rpc, node: &MockNode) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let endpoint = Endpoint::Tcp(node.addr());
+    while rpc.node() != &endpoint {
+        assert!(Instant::now() < deadline, "never switched to {}", endpoint);
+        std::thread::sleep(Duration::from_millis(50));
+        rpc.check_health().unwrap();
+    }
+}
+
+#[test]
+fn fails_over_to_node_ahead_and_back() {
+    let preferred = MockNode::start();
+    let backup = MockNode::start();
+    backup.set_height(5);
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &preferred.addr(),
+        "--rpc",
+        &backup.addr(),
+        "--rpc-health-check-interval",
+        "1",
+    ]))
+    .unwrap();
+    assert_eq!(rpc.node(), &Endpoint::Tcp(preferred.addr()));
+    wait_for_node(&mut rpc, &backup);
+    assert_eq!(rpc.get_height().unwrap(), 5);
+    preferred.set_height(5);
+    wait_for_node(&mut rpc, &preferred);
+}
+
+fn write_token_file(name: &str, token: &str) -> String {
+    let path = super::temp_path(name);
+    std::fs::write(&path, token).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+#[test]
+fn http_transport_with_token() {
+    let node = MockNode::start_http(Some("hunter2"));
+    let token_file = write_token_file("http-token", "hunter2\n");
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("http://{}/", node.addr()),
+        "--rpc-token-file",
+        &token_file,
+    ]))
+    .unwrap();
+    assert!(!rpc.subscribe());
+    assert_eq!(rpc.get_height().unwrap(), 1);
+    node.set_height(3);
+    assert_eq!(rpc.get_height().unwrap(), 3);
+    // All over the same keep-alive connection
+    assert_eq!(node.connections(), 1);
+    let _ = std::fs::remove_file(&token_file);
+}
+
+#[test]
+fn http_transport_never_resends_requests_the_node_got() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = std::thread::spawn(move || {
+        // Reads the whole request, then hangs up without answering
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = std::io::BufReader::new(stream);
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            let mut parts = line.splitn(2, ':');
+            if parts.next() == Some("content-length") {
+                len = parts.next().unwrap().trim().parse().unwrap();
+            }
+        }
+        stream.read_exact(&mut vec![0; len]).unwrap();
+        drop(stream);
+        listener.set_nonblocking(true).unwrap();
+        std::thread::sleep(Duration::from_millis(500));
+        listener.accept().is_ok()
+    });
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &format!("http://{}/", addr)])).unwrap();
+    assert!(rpc.start_request("merit_publishBlock", (0, "")).is_err());
+    assert!(!server.join().unwrap(), "request sent again");
+}
+
+#[test]
+fn http_transport_rejects_bad_token() {
+    let node = MockNode::start_http(Some("hunter2"));
+    let token_file = write_token_file("bad-http-token", "letmein");
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("http://{}/", node.addr()),
+        "--rpc-token-file",
+        &token_file,
+    ]))
+    .unwrap();
+    match rpc.start_request("merit_getHeight", [(); 0]) {
+        Err(RpcClientError::Connection(err))
+            if err.kind() == std::io::ErrorKind::PermissionDenied => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let _ = std::fs::remove_file(&token_file);
+}
+
+#[test]
+fn fails_to_connect_without_the_token_file() {
+    let node = MockNode::start_http(Some("hunter2"));
+    let token_file = super::temp_path("missing-token");
+    let res = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("http://{}/", node.addr()),
+        "--rpc-token-file",
+        token_file.to_str().unwrap(),
+    ]));
+    match res {
+        Err(RpcClientError::Setup(_, err)) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("connected without a token"),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket_transport() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("meroxidizer-{}.sock", std::process::id()));
+    let node = MockNode::start_unix(&path);
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    let mut rpc = Rpc::connect(test_opts(&["--rpc", &format!("unix://{}", node.addr())])).unwrap();
+    assert_eq!(rpc.node(), &Endpoint::Unix(path.clone()));
+    assert!(rpc.subscribe());
+    node.set_height(4);
+    assert_eq!(rpc.get_height().unwrap(), 4);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn fails_to_connect_without_the_recording() {
+    let node = MockNode::start();
+    let session = super::temp_path("missing-dir").join("session.jsonl");
+    let res = Rpc::connect(test_opts(&[
+        "--rpc",
+        &node.addr(),
+        "--rpc-record",
+        session.to_str().unwrap(),
+    ]));
+    match res {
+        Err(RpcClientError::Setup(..)) => {}
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("connected without the recording"),
+    }
+}
+
+#[test]
+fn records_and_replays_sessions() {
+    let node = MockNode::start();
+    let session = super::temp_path("session");
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &node.addr(),
+        "--rpc-record",
+        session.to_str().unwrap(),
+    ]))
+    .unwrap();
+    assert!(rpc.subscribe());
+    node.set_height(3);
+    let recorded = rpc
+        .notifications()
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap();
+    let (height, target) = rpc.poll_template("00").unwrap();
+    assert_eq!(height, 3);
+    drop(rpc);
+
+    let entries: Vec<Value> = Deserializer::from_reader(std::fs::File::open(&session).unwrap())
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    assert!(entries.iter().all(|e| e["time"].is_f64()));
+    let recorded_node = Endpoint::Tcp(node.addr()).to_string();
+    assert!(entries.iter().all(|e| e["node"] == json!(recorded_node)));
+    assert_eq!(entries[0]["direction"], "sent");
+    assert_eq!(entries[0]["message"]["method"], "subscribe");
+    assert!(entries.iter().any(|e| e["direction"] == "received"));
+
+    // In a different order, so the replies have to be matched up by method and given new ids
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("replay://{}", session.display()),
+    ]))
+    .unwrap();
+    let (replayed_height, replayed_target) = rpc.poll_template("00").unwrap();
+    assert_eq!(replayed_height, height);
+    assert_eq!(replayed_target.id, target.id);
+    assert_eq!(replayed_target.header, target.header);
+    assert!(rpc.subscribe());
+    let replayed = rpc.notifications().try_recv().unwrap();
+    assert_eq!(replayed.method, recorded.method);
+    assert_eq!(replayed.params, recorded.params);
+    // Nothing more was recorded, and retrying won't change that
+    match rpc.poll_template("00") {
+        Err(RpcClientError::ReplayExhausted(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map(|(height, _)| height)),
+    }
+
+    // Another client replays the session from the start
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("replay://{}", session.display()),
+    ]))
+    .unwrap();
+    assert_eq!(rpc.poll_template("00").unwrap().0, height);
+    let _ = std::fs::remove_file(&session);
+}
+
+#[test]
+fn never_probes_replayed_sessions() {
+    let node = MockNode::start();
+    let session = super::temp_path("probed-session");
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &node.addr(),
+        "--rpc-record",
+        session.to_str().unwrap(),
+    ]))
+    .unwrap();
+    node.set_height(3);
+    assert_eq!(rpc.get_height().unwrap(), 3);
+    node.set_height(4);
+    assert_eq!(rpc.get_height().unwrap(), 4);
+    drop(rpc);
+
+    // With another node, the replay would be health checked too
+    let other = MockNode::start();
+    let mut rpc = Rpc::connect(test_opts(&[
+        "--rpc",
+        &format!("replay://{}", session.display()),
+        "--rpc",
+        &other.addr(),
+        "--rpc-health-check-interval",
+        "1",
+    ]))
+    .unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(rpc.get_height().unwrap(), 3);
+    assert_eq!(rpc.get_height().unwrap(), 4);
+    let _ = std::fs::remove_file(&session);
+}
+
+#[test]
+fn parses_endpoints() {
+    assert_eq!(
+        "localhost:5133".parse(),
+        Ok(Endpoint::Tcp("localhost:5133".to_owned())),
+    );
+    assert_eq!(
+        "tcp://localhost:5133".parse(),
+        Ok(Endpoint::Tcp("localhost:5133".to_owned())),
+    );
+    assert_eq!(
+        "http://node.example".parse(),
+        Ok(Endpoint::Http {
+            host: "node.example:80".to_owned(),
+            path: "/".to_owned(),
+        }),
+    );
+    assert_eq!(
+        "http://127.0.0.1:5133/rpc".parse(),
+        Ok(Endpoint::Http {
+            host: "127.0.0.1:5133".to_owned(),
+            path: "/rpc".to_owned(),
+        }),
+    );
+    assert_eq!(
+        "unix:///run/meros/rpc.sock".parse(),
+        Ok(Endpoint::Unix("/run/meros/rpc.sock".into())),
+    );
+    assert!("unix://".parse::<Endpoint>().is_err());
+    assert_eq!(
+        "replay:///tmp/session.jsonl".parse(),
+        Ok(Endpoint::Replay("/tmp/session.jsonl".into())),
+    );
+    assert!("replay://".parse::<Endpoint>().is_err());
+    assert!("https://node.example".parse::<Endpoint>().is_err());
+}
@@ -0,0 +1,91 @@
+use crate::threads::{
+    supervisor::{Backoff, Supervisor},
+    ShutdownError,
+};
+use crossbeam_channel::{bounded, unbounded, RecvTimeoutError, TryRecvError};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn quick_backoff(max_restarts: u32) -> Backoff {
+    Backoff {
+        first: Duration::from_millis(1),
+        max: Duration::from_millis(10),
+        recovered_after: Duration::from_secs(60),
+        max_restarts,
+    }
+}
+
+#[test]
+fn restarts_failed_threads() {
+    let (stop_send, stop) = bounded::<()>(0);
+    let (started_send, started) = unbounded();
+    let runs = AtomicUsize::new(0);
+    let mut supervisor = Supervisor::new(quick_backoff(5), stop.clone());
+    supervisor.spawn("flaky", move || {
+        let run = runs.fetch_add(1, Ordering::SeqCst);
+        started_send.send(run).unwrap();
+        if run < 2 {
+            panic!("failing on purpose");
+        }
+        let _ = stop.recv();
+    });
+    let supervised = supervisor.start();
+    for run in 0..3 {
+        assert_eq!(started.recv_timeout(TIMEOUT), Ok(run));
+    }
+    assert_eq!(supervised.gave_up().try_recv(), Err(TryRecvError::Empty));
+    // Stopping once asked to isn't a failure, so it isn't restarted
+    drop(stop_send);
+    supervised.join(Instant::now() + TIMEOUT).unwrap();
+    assert_eq!(started.try_recv(), Err(TryRecvError::Disconnected));
+}
+
+#[test]
+fn gives_up_on_threads_which_keep_failing() {
+    let (stop_send, stop) = bounded::<()>(0);
+    let runs = Arc::new(AtomicUsize::new(0));
+    let mut supervisor = Supervisor::new(quick_backoff(3), stop);
+    let runs2 = runs.clone();
+    supervisor.spawn("broken", move || {
+        runs2.fetch_add(1, Ordering::SeqCst);
+        panic!("failing on purpose");
+    });
+    let supervised = supervisor.start();
+    assert_eq!(
+        supervised.gave_up().recv_timeout(TIMEOUT),
+        Err(RecvTimeoutError::Disconnected),
+    );
+    // Started once, then restarted as many times as allowed
+    assert_eq!(runs.load(Ordering::SeqCst), 4);
+    drop(stop_send);
+    match supervised.join(Instant::now() + TIMEOUT) {
+        Err(ShutdownError::GaveUp("broken")) => {}
+        res => panic!("expected to give up on the thread, got {:?}", res),
+    }
+}
+
+#[test]
+fn stopping_first_isnt_a_failure() {
+    // Each round, the supervisor has the exit and the stop to pick from, in whichever order
+    for _ in 0..8 {
+        let (stop_send, stop) = bounded::<()>(0);
+        // Without restarts, an exit taken for a failure would give up on mining
+        let mut supervisor = Supervisor::new(quick_backoff(0), stop.clone());
+        let (exited_send, exited) = unbounded();
+        supervisor.spawn("stopping", move || {
+            let _ = stop.recv();
+            exited_send.send(()).unwrap();
+        });
+        drop(stop_send);
+        exited.recv_timeout(TIMEOUT).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        supervisor.start().join(Instant::now() + TIMEOUT).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{
+    rpc::RpcMiningTarget,
+    template::{new_miner_header, validate, BlockHeader, HeaderMiner, InvalidTemplate},
+};
+
+/// A header with every field set apart, naming the miner with `miner` after the flag byte.
+fn header(flag: u8, miner: &[u8]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend(&1u32.to_be_bytes());
+    header.extend(&[0xaa; 32]);
+    header.extend(&[0xbb; 32]);
+    header.extend(&5u16.to_be_bytes());
+    header.extend(&[0xcc; 4]);
+    header.extend(&[0xdd; 32]);
+    header.push(flag);
+    header.extend(miner);
+    header.extend(&1234u32.to_be_bytes());
+    header
+}
+
+fn pubkey() -> Vec<u8> {
+    vec![3; 96]
+}
+
+fn target(header: Vec<u8>, difficulty: u64) -> RpcMiningTarget {
+    RpcMiningTarget {
+        id: 1,
+        key: [0; 32],
+        header,
+        difficulty,
+    }
+}
+
+#[test]
+fn parses_new_miner_header() {
+    let bytes = header(1, &pubkey());
+    assert_eq!(bytes.len(), 207);
+    let parsed = BlockHeader::parse(&bytes).unwrap();
+    assert_eq!(parsed.version, 1);
+    assert_eq!(parsed.last, &[0xaa; 32][..]);
+    assert_eq!(parsed.contents, &[0xbb; 32][..]);
+    assert_eq!(parsed.significant, 5);
+    assert_eq!(parsed.sketch_salt, &[0xcc; 4][..]);
+    assert_eq!(parsed.sketch_check, &[0xdd; 32][..]);
+    assert_eq!(parsed.miner, HeaderMiner::Key(&pubkey()));
+    assert_eq!(parsed.time, 1234);
+}
+
+#[test]
+fn parses_nick_header() {
+    let bytes = header(0, &[0, 9]);
+    assert_eq!(bytes.len(), 113);
+    let parsed = BlockHeader::parse(&bytes).unwrap();
+    assert_eq!(parsed.miner, HeaderMiner::Nick(9));
+    assert_eq!(parsed.time, 1234);
+}
+
+#[test]
+fn rejects_bad_lengths() {
+    let mut bytes = header(1, &pubkey());
+    bytes.pop();
+    assert_eq!(
+        BlockHeader::parse(&bytes).unwrap_err(),
+        InvalidTemplate::Length {
+            expected: 207,
+            actual: 206,
+        },
+    );
+    // A nick where a key should be
+    let bytes = header(1, &[0, 9]);
+    assert_eq!(
+        BlockHeader::parse(&bytes).unwrap_err(),
+        InvalidTemplate::Length {
+            expected: 207,
+            actual: 113,
+        },
+    );
+    assert_eq!(
+        BlockHeader::parse(&[0; 32]).unwrap_err(),
+        InvalidTemplate::Length {
+            expected: 113,
+            actual: 32,
+        },
+    );
+}
+
+#[test]
+fn rejects_bad_miner_flag() {
+    assert_eq!(
+        BlockHeader::parse(&header(2, &[0, 9])).unwrap_err(),
+        InvalidTemplate::MinerFlag(2),
+    );
+}
+
+#[test]
+fn validates_targets() {
+    let ours = pubkey();
+    assert!(validate(&target(header(1, &ours), 10), &ours).is_ok());
+    assert!(validate(&target(header(0, &[0, 9]), 10), &ours).is_ok());
+    assert_eq!(
+        validate(&target(header(1, &[4; 96]), 10), &ours).unwrap_err(),
+        InvalidTemplate::WrongMinerKey,
+    );
+    assert_eq!(
+        validate(&target(header(1, &ours), 0), &ours).unwrap_err(),
+        InvalidTemplate::ZeroDifficulty,
+    );
+}
+
+#[test]
+fn builds_new_miner_headers() {
+    let header = new_miner_header(&pubkey());
+    let parsed = BlockHeader::parse(&header).unwrap();
+    assert_eq!(parsed.miner, HeaderMiner::Key(&pubkey()));
+    assert_eq!(parsed.time, 0);
+}
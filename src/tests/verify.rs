@@ -0,0 +1,84 @@
+use super::{
+    light_vm,
+    mock_node::{miner_pubkey, MockTemplate, MINER_KEY},
+};
+use crate::{
+    bls::{PublicKey, SecretKey, SIG_SIZE},
+    verify::{verify, Verification},
+};
+use randomx::Vm;
+
+const KEY: [u8; 32] = [1; 32];
+const NONCE: u32 = 7;
+
+/// A block for a new miner, signed with `MINER_KEY`.
+fn signed_block(vm: &mut Vm) -> (Vec<u8>, [u8; SIG_SIZE]) {
+    let header = MockTemplate::new(1, 1, KEY, miner_pubkey(), 1).header;
+    let mut input = header.clone();
+    input.extend(&NONCE.to_le_bytes());
+    let sig = SecretKey::new(&MINER_KEY).unwrap().sign(&vm.hash(&input));
+    (header, sig)
+}
+
+fn check(
+    vm: &mut Vm,
+    header: &[u8],
+    nonce: u32,
+    sig: &[u8; SIG_SIZE],
+    difficulty: u64,
+) -> Verification {
+    let key = PublicKey::new(&miner_pubkey()).unwrap();
+    verify(vm, header, nonce, sig, Some(&key), Some(difficulty))
+}
+
+#[test]
+fn accepts_a_signed_block() {
+    let mut vm = light_vm(&KEY);
+    let (header, sig) = signed_block(&mut vm);
+    let verification = check(&mut vm, &header, NONCE, &sig, 1);
+    assert_eq!(verification.signature_valid, Some(true));
+    assert_eq!(verification.meets_difficulty, Some(true));
+    assert!(verification.valid());
+
+    let mut second_input = verification.first_hash.to_vec();
+    second_input.extend(&sig as &[u8]);
+    assert_eq!(verification.second_input, second_input);
+    assert_eq!(verification.second_hash, vm.hash(&second_input));
+}
+
+#[test]
+fn rejects_a_signature_of_another_nonce() {
+    let mut vm = light_vm(&KEY);
+    let (header, sig) = signed_block(&mut vm);
+    let verification = check(&mut vm, &header, NONCE + 1, &sig, 1);
+    assert_eq!(verification.signature_valid, Some(false));
+    assert!(!verification.valid());
+}
+
+#[test]
+fn rejects_a_malformed_signature() {
+    let mut vm = light_vm(&KEY);
+    let (header, _) = signed_block(&mut vm);
+    let verification = check(&mut vm, &header, NONCE, &[0xff; SIG_SIZE], 1);
+    assert_eq!(verification.signature_valid, Some(false));
+    assert!(!verification.valid());
+}
+
+#[test]
+fn rejects_a_hash_above_the_max() {
+    let mut vm = light_vm(&KEY);
+    let (header, sig) = signed_block(&mut vm);
+    let verification = check(&mut vm, &header, NONCE, &sig, u64::MAX);
+    assert_eq!(verification.signature_valid, Some(true));
+    assert_eq!(verification.meets_difficulty, Some(false));
+    assert!(!verification.valid());
+}
+
+#[test]
+fn checks_what_it_can_without_a_key() {
+    let mut vm = light_vm(&KEY);
+    let (header, sig) = signed_block(&mut vm);
+    let verification = verify(&mut vm, &header, NONCE, &sig, None, Some(1));
+    assert_eq!(verification.signature_valid, None);
+    assert!(verification.valid());
+}
@@ -0,0 +1,217 @@
+//! Moving threads between BLS signing and RandomX, with `--auto-balance`.
+//!
+//! Both stages feed each other through bounded channels, so whichever is slower shows up there.
+//! Signers falling behind leave RandomX threads with first hashes but no room to send them,
+//! and RandomX falling behind leaves signers with nothing to sign.
+
+use super::{
+    hasher, rpc_manager::RpcInfo, signer, PartialHashBatch, Supervisor, HASH_CHAN_CAPACITY,
+};
+use crate::bls::SIG_SIZE;
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use log::{debug, info};
+use randomx::HASH_SIZE;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+/// How many samples to decide on, 10 seconds' worth.
+const SAMPLES: usize = 200;
+
+/// Which threads sign, and which run RandomX.
+pub struct Balance {
+    /// Threads with an index below this sign.
+    signers: AtomicUsize,
+    total: usize,
+    /// By index, a message once the thread's been moved, to wake it up if it's blocked.
+    moved: Vec<(Sender<()>, Receiver<()>)>,
+}
+
+impl Balance {
+    pub fn new(signers: usize, total: usize) -> Balance {
+        Balance {
+            signers: AtomicUsize::new(signers),
+            total,
+            moved: (0..total).map(|_| bounded(1)).collect(),
+        }
+    }
+
+    fn signers(&self) -> usize {
+        self.signers.load(Ordering::Relaxed)
+    }
+
+    fn set_signers(&self, signers: usize) {
+        let old = self.signers.swap(signers, Ordering::Relaxed);
+        for (send, _) in &self.moved[old.min(signers)..old.max(signers)] {
+            let _ = send.try_send(());
+        }
+    }
+
+    /// The thread with `index`, out of `total`.
+    pub fn assign(self: &Arc<Self>, index: usize) -> Assignment {
+        Assignment {
+            balance: self.clone(),
+            index,
+        }
+    }
+}
+
+/// A thread's place in the balance, to know when it's been moved.
+pub struct Assignment {
+    balance: Arc<Balance>,
+    index: usize,
+}
+
+impl Assignment {
+    pub fn signing(&self) -> bool {
+        self.index < self.balance.signers()
+    }
+
+    /// Receives a message once the thread's been moved, to select on alongside blocking operations.
+    /// It may also receive one for an earlier move, so check `signing` after.
+    pub fn moved(&self) -> &Receiver<()> {
+        &self.balance.moved[self.index].1
+    }
+}
+
+/// How many of `total` threads should sign, given how many of `samples` caught
+/// the signers or RandomX behind. Only clear bottlenecks move a thread, one at a time,
+/// and each stage keeps at least one thread.
+pub fn rebalance(
+    signers: usize,
+    total: usize,
+    signers_behind: usize,
+    randomx_behind: usize,
+    samples: usize,
+) -> usize {
+    let clear = |behind: usize| behind * 4 > samples * 3;
+    if clear(signers_behind) && signers + 1 < total {
+        signers + 1
+    } else if clear(randomx_behind) && signers > 1 {
+        signers - 1
+    } else {
+        signers
+    }
+}
+
+fn run(
+    rpc_info: Arc<RpcInfo>,
+    balance: Arc<Balance>,
+    first: Receiver<PartialHashBatch<[u8; HASH_SIZE]>>,
+    second: Receiver<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+) {
+    let mut moved = false;
+    loop {
+        let mut signers_behind = 0;
+        let mut randomx_behind = 0;
+        for _ in 0..SAMPLES {
+            select! {
+                recv(rpc_info.stop) -> _ => return,
+                default(SAMPLE_INTERVAL) => {},
+            }
+            // RandomX threads take on second hashes before first ones
+            if first.len() == HASH_CHAN_CAPACITY && second.is_empty() {
+                signers_behind += 1;
+            } else if first.is_empty() || second.len() == HASH_CHAN_CAPACITY {
+                randomx_behind += 1;
+            }
+        }
+        debug!(
+            "signers behind {}/{} samples, RandomX behind {}/{}",
+            signers_behind, SAMPLES, randomx_behind, SAMPLES,
+        );
+        let signers = balance.signers();
+        let target = rebalance(
+            signers,
+            balance.total,
+            signers_behind,
+            randomx_behind,
+            SAMPLES,
+        );
+        if target != signers {
+            balance.set_signers(target);
+            info!(
+                "rebalanced to {} BLS and {} RandomX threads",
+                target,
+                balance.total - target,
+            );
+            moved = true;
+        } else if moved {
+            info!(
+                "settled on {} BLS and {} RandomX threads",
+                signers,
+                balance.total - signers,
+            );
+            moved = false;
+        }
+    }
+}
+
+/// Starts a thread which signs or runs RandomX, as the balance says.
+pub fn start_thread(
+    supervisor: &mut Supervisor,
+    rpc_info: Arc<RpcInfo>,
+    assignment: Assignment,
+    first_send: Sender<PartialHashBatch<[u8; HASH_SIZE]>>,
+    first_recv: Receiver<PartialHashBatch<[u8; HASH_SIZE]>>,
+    second_send: Sender<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+    second_recv: Receiver<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+) {
+    supervisor.spawn("balanced", move || {
+        let node = rpc_info.placement.pin_balanced(assignment.index);
+        loop {
+            let signing = assignment.signing();
+            if signing {
+                signer::run(
+                    rpc_info.clone(),
+                    first_recv.clone(),
+                    second_send.clone(),
+                    Some(&assignment),
+                );
+            } else {
+                let worker = rpc_info.pause.register();
+                let unsent = hasher::run(
+                    rpc_info.clone(),
+                    worker,
+                    node,
+                    first_send.clone(),
+                    second_recv.clone(),
+                    Some(&assignment),
+                );
+                // Its first hashes are ours to sign now
+                if let Some(batch) = unsent {
+                    if !signer::sign_and_send(&rpc_info, &batch, &second_send) {
+                        return;
+                    }
+                }
+            }
+            // Otherwise, it stopped for good
+            if assignment.signing() == signing {
+                return;
+            }
+        }
+    });
+}
+
+/// Starts the thread watching the channels and moving threads between stages.
+pub fn start(
+    supervisor: &mut Supervisor,
+    rpc_info: Arc<RpcInfo>,
+    balance: Arc<Balance>,
+    first: Receiver<PartialHashBatch<[u8; HASH_SIZE]>>,
+    second: Receiver<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+) {
+    supervisor.spawn("balancer", move || {
+        run(
+            rpc_info.clone(),
+            balance.clone(),
+            first.clone(),
+            second.clone(),
+        )
+    });
+}
@@ -0,0 +1,242 @@
+//! Measuring how fast this machine mines, without a node.
+//!
+//! Each stage is measured on its own first, then the whole pipeline together,
+//! all on a made up template with a target no hash meets, so nothing is ever published.
+
+use super::{
+    check_thread_counts, hasher,
+    rpc_manager::{self, RpcInfo},
+    signer, start_workers,
+    supervisor::{Backoff, Supervisor},
+    HASH_CHAN_BATCH_SIZE,
+};
+use crate::{bls::SecretKey, cli::Opts, placement::Placement, template};
+use crossbeam_channel::bounded;
+use eyre::{eyre, Report, WrapErr};
+use log::info;
+use randomx::{Cache, Vm};
+use serde::Serialize;
+use std::{
+    sync::{atomic, Arc},
+    time::{Duration, Instant},
+};
+
+/// The RandomX key everything is hashed with.
+const KEY: [u8; 32] = [0; 32];
+const MINER_KEY: [u8; 32] = [1; 32];
+/// How long the pipeline gets to stop after a run.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the pipeline gets to finish a batch, before it's taken to be stuck.
+const BATCH_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the pipeline is checked on for finished batches.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A header of zeroes from a miner without a nickname yet, by the public key of `MINER_KEY`.
+fn header() -> Vec<u8> {
+    template::new_miner_header(&SecretKey::new(&MINER_KEY).unwrap().get_public_key())
+}
+
+/// How fast a split of threads mined end to end, in hashes per second.
+#[derive(Clone, Debug, Serialize)]
+pub struct Split {
+    pub bls_threads: usize,
+    pub randomx_threads: usize,
+    pub end_to_end: f64,
+}
+
+/// Rates are per second, for all the threads of a stage together.
+#[derive(Clone, Debug, Serialize)]
+pub struct Results {
+    pub bls_threads: usize,
+    pub randomx_threads: usize,
+    pub first_hashes: f64,
+    pub signatures: f64,
+    pub second_hashes: f64,
+    /// Second hashes of the pipeline, each a block checked against the target.
+    pub end_to_end: f64,
+    /// With a sweep, every other split of the same number of threads.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sweep: Vec<Split>,
+}
+
+/// Runs `run` on `threads` threads with their index and a deadline,
+/// returning how many of whatever they count they got through per second.
+fn measure<F>(threads: usize, duration: Duration, run: F) -> f64
+where
+    F: Fn(usize, Instant) -> usize + Send + Sync + 'static,
+{
+    let run = Arc::new(run);
+    let start = Instant::now();
+    let deadline = start + duration;
+    let handles: Vec<_> = (0..threads)
+        .map(|i| {
+            let run = run.clone();
+            std::thread::spawn(move || run(i, deadline))
+        })
+        .collect();
+    let total: usize = handles
+        .into_iter()
+        .map(|h| h.join().expect("Benchmark thread panicked"))
+        .sum();
+    total as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Waits for the pipeline to finish a batch after the first `seen`,
+/// returning how many it finished by then, and when.
+fn next_batch(rpc_info: &RpcInfo, seen: usize) -> Result<(usize, Instant), Report> {
+    let deadline = Instant::now() + BATCH_TIMEOUT;
+    loop {
+        let batches = rpc_info.num_hashes_rec.load(atomic::Ordering::Relaxed);
+        if batches > seen {
+            return Ok((batches, Instant::now()));
+        }
+        if Instant::now() > deadline {
+            return Err(eyre!("The pipeline stopped finishing batches"));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Times the pipeline from the end of one batch to the end of another, at least `duration` later,
+/// leaving out starting up, and the batches in progress at either end.
+fn time_pipeline(rpc_info: &RpcInfo, duration: Duration) -> Result<f64, Report> {
+    let (first, start) = next_batch(rpc_info, 0)?;
+    std::thread::sleep(duration);
+    let seen = rpc_info.num_hashes_rec.load(atomic::Ordering::Relaxed);
+    let (last, end) = next_batch(rpc_info, seen)?;
+    let batches = last - first;
+    Ok((batches * HASH_CHAN_BATCH_SIZE) as f64 / (end - start).as_secs_f64())
+}
+
+fn end_to_end(
+    opts: &Opts,
+    placement: &Arc<Placement>,
+    caches: &[Arc<Cache>],
+    duration: Duration,
+) -> Result<f64, Report> {
+    let (stop_send, stop) = bounded(0);
+    let rpc_info = rpc_manager::offline(
+        placement.clone(),
+        SecretKey::new(&MINER_KEY).unwrap(),
+        header(),
+        caches.to_vec(),
+        stop.clone(),
+    );
+    let mut supervisor = Supervisor::new(Backoff::default(), stop);
+    start_workers(&mut supervisor, opts, &rpc_info);
+    let workers = supervisor.start();
+    let rate = time_pipeline(&rpc_info, duration);
+    drop(stop_send);
+    workers
+        .join(Instant::now() + STOP_TIMEOUT)
+        .wrap_err("Failed to stop benchmark")?;
+    rate
+}
+
+/// Measures each stage and the pipeline for `duration` each, with the thread options.
+/// A sweep measures every other split of --bls-threads plus --randomx-threads too, end to end.
+pub fn run(opts: &Opts, duration: Duration, sweep: bool) -> Result<Results, Report> {
+    check_thread_counts(opts)?;
+    let placement = Arc::new(Placement::new(opts)?);
+    info!("initializing RandomX..");
+    let caches = placement
+        .new_caches(opts.get_randomx_flags(), &KEY, opts.randomx_init_threads)
+        .wrap_err("Failed to initialize RandomX")?;
+    // The stages on their own never stop early, so they don't need to be told to
+    let (_stop_send, stop) = bounded(0);
+    let rpc_info = rpc_manager::offline(
+        placement.clone(),
+        SecretKey::new(&MINER_KEY).unwrap(),
+        header(),
+        caches.clone(),
+        stop,
+    );
+    let template = rpc_info.latest_template.read().clone();
+    let mut vm = Vm::new(caches[0].clone()).wrap_err("Failed to create RandomX VM")?;
+    let first = match hasher::first_batch(&rpc_info, &mut vm, &template) {
+        Ok(first) => first,
+        Err(_) => unreachable!("fresh template ran out of nonces"),
+    };
+    let signed = Arc::new(signer::sign(&rpc_info, &first).unwrap());
+    let first = Arc::new(first);
+    drop(vm);
+
+    info!("measuring first hashes..");
+    let info = rpc_info.clone();
+    let template2 = template.clone();
+    let first_hashes = measure(opts.randomx_threads, duration, move |i, deadline| {
+        let node = info.placement.pin_randomx(i);
+        let mut vm = Vm::new(template2.randomx_caches[node].clone()).unwrap();
+        let mut hashes = 0;
+        while Instant::now() < deadline {
+            if hasher::first_batch(&info, &mut vm, &template2).is_ok() {
+                hashes += HASH_CHAN_BATCH_SIZE;
+            }
+        }
+        hashes
+    });
+
+    info!("measuring signing..");
+    let info = rpc_info.clone();
+    let signatures = measure(opts.bls_threads, duration, move |i, deadline| {
+        info.placement.pin_signer(i);
+        let mut signatures = 0;
+        while Instant::now() < deadline {
+            if signer::sign(&info, &first).is_some() {
+                signatures += HASH_CHAN_BATCH_SIZE;
+            }
+        }
+        signatures
+    });
+
+    info!("measuring second hashes..");
+    let info = rpc_info.clone();
+    let second_hashes = measure(opts.randomx_threads, duration, move |i, deadline| {
+        let node = info.placement.pin_randomx(i);
+        let worker = info.pause.register();
+        let mut vm = Vm::new(template.randomx_caches[node].clone()).unwrap();
+        let mut hashes = 0;
+        while Instant::now() < deadline {
+            hasher::second_batch(&info, &worker, &mut vm, &template, &signed);
+            hashes += HASH_CHAN_BATCH_SIZE;
+        }
+        hashes
+    });
+    drop(rpc_info);
+
+    info!("measuring the pipeline..");
+    let end_to_end_rate = end_to_end(opts, &placement, &caches, duration)?;
+    let mut splits = Vec::new();
+    if sweep {
+        let total = opts.bls_threads + opts.randomx_threads;
+        for bls_threads in 1..total {
+            let randomx_threads = total - bls_threads;
+            let rate = if bls_threads == opts.bls_threads {
+                end_to_end_rate
+            } else {
+                info!(
+                    "measuring the pipeline with {} BLS and {} RandomX threads..",
+                    bls_threads, randomx_threads,
+                );
+                let mut split_opts = opts.clone();
+                split_opts.bls_threads = bls_threads;
+                split_opts.randomx_threads = randomx_threads;
+                end_to_end(&split_opts, &placement, &caches, duration)?
+            };
+            splits.push(Split {
+                bls_threads,
+                randomx_threads,
+                end_to_end: rate,
+            });
+        }
+    }
+    Ok(Results {
+        bls_threads: opts.bls_threads,
+        randomx_threads: opts.randomx_threads,
+        first_hashes,
+        signatures,
+        second_hashes,
+        end_to_end: end_to_end_rate,
+        sweep: splits,
+    })
+}
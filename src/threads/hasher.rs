@@ -0,0 +1,294 @@
+//! The RandomX threads, each taking on whichever of a block's two hashes there's work for.
+//!
+//! Between the hashes, the signers sign the first one. Second hashes come first,
+//! as half the work for their blocks is already done. Otherwise, the threads keep the signers fed.
+
+use super::{
+    balancer::Assignment,
+    pause::Worker,
+    rpc_manager::{BlockTemplate, Found, RpcInfo},
+    PartialHashBatch, Supervisor, HASH_CHAN_BATCH_SIZE,
+};
+use crate::{bls::SIG_SIZE, utils::less_than_rev};
+use crossbeam_channel::{never, select, Receiver, Sender, TryRecvError};
+use log::trace;
+use randomx::{HashChain, Vm, HASH_SIZE};
+use std::{
+    sync::{atomic, Arc},
+    time::Duration,
+};
+
+/// How often to check for a fresh template, once every nonce of ours was claimed.
+const EXHAUSTED_RECHECK: Duration = Duration::from_millis(100);
+
+/// Why `first_batch` came back without one.
+pub(super) enum NoBatch {
+    /// The template went stale partway through, so the rest would have too.
+    CutShort,
+    /// Every nonce of the template was claimed.
+    Exhausted,
+}
+
+pub(super) fn first_batch(
+    rpc_info: &RpcInfo,
+    vm: &mut Vm,
+    template: &BlockTemplate,
+) -> Result<PartialHashBatch<[u8; HASH_SIZE]>, NoBatch> {
+    let mut nonce = match template.nonces.claim() {
+        Some(nonce) => nonce,
+        None => return Err(NoBatch::Exhausted),
+    };
+    trace!(
+        "first hashing nonces from {} with template seq {}",
+        nonce,
+        template.seq,
+    );
+    let mut batch = PartialHashBatch {
+        seq: template.seq,
+        height: template.height,
+        key_epoch: template.key_epoch,
+        items: [(0, [0; HASH_SIZE]); HASH_CHAN_BATCH_SIZE],
+    };
+    let mut input = template.header.clone();
+    input.extend(&nonce.to_le_bytes());
+    let mut hash_chain = HashChain::new(vm, &input);
+    for (i, out) in batch.items[..(HASH_CHAN_BATCH_SIZE - 1)]
+        .iter_mut()
+        .enumerate()
+    {
+        if rpc_info.is_stale(template.seq, template.height) {
+            rpc_info
+                .stale_hashes
+                .fetch_add(i, atomic::Ordering::Relaxed);
+            return Err(NoBatch::CutShort);
+        }
+        let prev_nonce = nonce;
+        nonce = nonce.wrapping_add(1);
+        input[template.header.len()..].copy_from_slice(&nonce.to_le_bytes());
+        let prev_hash = hash_chain.next(&input);
+        *out = (prev_nonce, prev_hash);
+    }
+    batch.items[batch.items.len() - 1] = (nonce, hash_chain.last());
+    Ok(batch)
+}
+
+/// Hands a found block to the RPC thread, unless a pause comes first,
+/// as it would have been found with the old key.
+/// Returns false if the RPC thread is gone, or we're stopping.
+fn publish(rpc_info: &RpcInfo, worker: &Worker, found: Found) -> bool {
+    loop {
+        select! {
+            send(rpc_info.publish_channel, found) -> res => return res.is_ok(),
+            recv(worker.wake()) -> _ => if worker.requested() {
+                return true;
+            },
+            recv(rpc_info.stop) -> _ => return false,
+        }
+    }
+}
+
+/// Returns false if the RPC thread is gone, or we're stopping.
+pub(super) fn second_batch(
+    rpc_info: &RpcInfo,
+    worker: &Worker,
+    vm: &mut Vm,
+    template: &BlockTemplate,
+    inputs: &PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>,
+) -> bool {
+    let mut prev_input = inputs.items[0];
+    let mut hash_chain = HashChain::new(vm, &prev_input.1);
+    trace!("second hashing with template seq {}", template.seq);
+    for (i, input) in inputs.items[1..].iter().enumerate() {
+        if rpc_info.is_stale(inputs.seq, inputs.height) {
+            // Along with the batch's first hashes
+            rpc_info
+                .stale_hashes
+                .fetch_add(HASH_CHAN_BATCH_SIZE + i, atomic::Ordering::Relaxed);
+            return true;
+        }
+        let out = hash_chain.next(&input.1);
+        if less_than_rev(&out, &template.max_hash) {
+            let mut sig = [0u8; SIG_SIZE];
+            sig.copy_from_slice(&prev_input.1[HASH_SIZE..]);
+            if !publish(rpc_info, worker, (inputs.seq, prev_input.0, sig, out)) {
+                return false;
+            }
+            if worker.requested() {
+                // The rest of the batch is for the old key too
+                return true;
+            }
+        }
+        prev_input = *input;
+    }
+    let out = hash_chain.last();
+    if less_than_rev(&out, &template.max_hash) {
+        let mut sig = [0u8; SIG_SIZE];
+        sig.copy_from_slice(&prev_input.1[HASH_SIZE..]);
+        if !publish(rpc_info, worker, (inputs.seq, prev_input.0, sig, out)) {
+            return false;
+        }
+    }
+    rpc_info
+        .num_hashes_rec
+        .fetch_add(1, atomic::Ordering::Relaxed);
+    true
+}
+
+/// Switches to the latest template and its cache, parking first if a pause was requested.
+fn reload(
+    rpc_info: &RpcInfo,
+    worker: &Worker,
+    node: usize,
+    template: Arc<BlockTemplate>,
+    vm: Vm,
+) -> (Arc<BlockTemplate>, Vm) {
+    std::mem::drop(template);
+    let vm_no_cache = vm.drop_cache();
+    if worker.requested() {
+        worker.park();
+    }
+    let template = rpc_info.latest_template.read().clone();
+    let vm = vm_no_cache
+        .set_cache(template.randomx_caches[node].clone())
+        .expect("Failed to set RandomX cache");
+    (template, vm)
+}
+
+/// Hashes with the RandomX cache for NUMA `node`.
+/// Returns once stopping, or once `assignment` says to sign instead,
+/// with the first batch it hadn't sent yet, if any.
+pub(super) fn run(
+    rpc_info: Arc<RpcInfo>,
+    worker: Worker,
+    node: usize,
+    first_out: Sender<PartialHashBatch<[u8; HASH_SIZE]>>,
+    second_in: Receiver<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+    assignment: Option<&Assignment>,
+) -> Option<PartialHashBatch<[u8; HASH_SIZE]>> {
+    // Restarted during a pause, so the cache mustn't be touched until it's over
+    if worker.requested() {
+        worker.park();
+    }
+    let unassigned = never();
+    let moved = assignment.map_or(&unassigned, Assignment::moved);
+    let mut template = rpc_info.latest_template.read().clone();
+    let mut vm =
+        Vm::new(template.randomx_caches[node].clone()).expect("Failed to create RandomX VM");
+    // A first batch waiting on the signers to catch up
+    let mut unsent = None;
+    // The last template we told the RPC thread ran out of nonces
+    let mut exhausted_seq = None;
+    loop {
+        let inputs = match second_in.try_recv() {
+            Ok(inputs) => Some(inputs),
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {
+                let mut cut_short = false;
+                if unsent.is_none() {
+                    match first_batch(&rpc_info, &mut vm, &template) {
+                        Ok(batch) => unsent = Some(batch),
+                        Err(NoBatch::CutShort) => cut_short = true,
+                        Err(NoBatch::Exhausted) => {}
+                    }
+                }
+                if cut_short {
+                    // Straight to the fresh template
+                    None
+                } else if unsent.is_none() {
+                    if exhausted_seq != Some(template.seq) {
+                        exhausted_seq = Some(template.seq);
+                        let _ = rpc_info.exhausted.try_send(template.seq);
+                    }
+                    // Only second hashes are left to do until there's a fresh template
+                    select! {
+                        recv(second_in) -> inputs => match inputs {
+                            Ok(x) => Some(x),
+                            Err(_) => return None,
+                        },
+                        recv(worker.wake()) -> _ => None,
+                        recv(moved) -> _ => None,
+                        recv(rpc_info.stop) -> _ => return None,
+                        default(EXHAUSTED_RECHECK) => None,
+                    }
+                } else {
+                    // The batch is only taken if there's room for it
+                    select! {
+                        send(first_out, unsent.take().unwrap()) -> res => match res {
+                            Ok(()) => None,
+                            Err(_) => return None,
+                        },
+                        recv(second_in) -> inputs => match inputs {
+                            Ok(x) => Some(x),
+                            Err(_) => return None,
+                        },
+                        recv(worker.wake()) -> _ => None,
+                        recv(moved) -> _ => None,
+                        recv(rpc_info.stop) -> _ => return None,
+                    }
+                }
+            }
+        };
+        if let Some(inputs) = inputs {
+            if inputs.seq > template.seq {
+                // Other threads got to a new template before us
+                let (t, v) = reload(&rpc_info, &worker, node, template, vm);
+                template = t;
+                vm = v;
+            }
+            if rpc_info.is_stale(inputs.seq, inputs.height) {
+                // Stale, but through no fault of the key
+                rpc_info
+                    .stale_hashes
+                    .fetch_add(HASH_CHAN_BATCH_SIZE, atomic::Ordering::Relaxed);
+            } else if inputs.key_epoch != template.key_epoch {
+                // Its first hashes are with another key than our VM's, so it'd make invalid proofs
+                rpc_info
+                    .discarded_batches
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            } else if !second_batch(&rpc_info, &worker, &mut vm, &template, &inputs) {
+                return None;
+            }
+        }
+        if worker.requested() {
+            // An unsent batch is of no use after a pause, as it's for the old key
+            unsent = None;
+        } else if matches!(&unsent, Some(b) if rpc_info.is_stale(b.seq, b.height)) {
+            unsent = None;
+            rpc_info
+                .stale_hashes
+                .fetch_add(HASH_CHAN_BATCH_SIZE, atomic::Ordering::Relaxed);
+        }
+        if matches!(assignment, Some(a) if a.signing()) {
+            return unsent;
+        }
+        if worker.requested() || rpc_info.latest_seq.load(atomic::Ordering::Relaxed) > template.seq
+        {
+            let (t, v) = reload(&rpc_info, &worker, node, template, vm);
+            template = t;
+            vm = v;
+        }
+    }
+}
+
+/// Starts the `index`th RandomX thread.
+pub fn start(
+    supervisor: &mut Supervisor,
+    rpc_info: Arc<RpcInfo>,
+    index: usize,
+    first_out: Sender<PartialHashBatch<[u8; HASH_SIZE]>>,
+    second_in: Receiver<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+) {
+    supervisor.spawn("RandomX", move || {
+        let node = rpc_info.placement.pin_randomx(index);
+        // Registered before touching the template, so a pause can't miss it
+        let worker = rpc_info.pause.register();
+        run(
+            rpc_info.clone(),
+            worker,
+            node,
+            first_out.clone(),
+            second_in.clone(),
+            None,
+        );
+    });
+}
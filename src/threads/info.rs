@@ -1,5 +1,6 @@
-use super::{rpc_manager::RpcInfo, HASH_CHAN_BATCH_SIZE};
-use log::info;
+use super::{rpc_manager::RpcInfo, Supervisor, HASH_CHAN_BATCH_SIZE};
+use crossbeam_channel::select;
+use log::{info, warn};
 use std::{
     sync::{atomic, Arc},
     time::Duration,
@@ -8,16 +9,59 @@ use std::{
 const OUTPUT_INFO_INTERVAL: Duration = Duration::from_secs(30);
 
 fn run(rpc_info: Arc<RpcInfo>) {
+    let mut last_stalls = 0;
+    let mut last_invalid_templates = 0;
+    let mut last_discarded_batches = 0;
+    let mut last_stale_hashes = 0;
     loop {
-        std::thread::sleep(OUTPUT_INFO_INTERVAL);
+        select! {
+            recv(rpc_info.stop) -> _ => return,
+            default(OUTPUT_INFO_INTERVAL) => {},
+        }
         let new_hashes = rpc_info.num_hashes_rec.swap(0, atomic::Ordering::Relaxed);
         info!(
             "current hashrate: {} H/s",
             (new_hashes * HASH_CHAN_BATCH_SIZE) / (OUTPUT_INFO_INTERVAL.as_secs() as usize),
         );
+        let stalls = rpc_info.rpc_stalls.load(atomic::Ordering::Relaxed);
+        if stalls != last_stalls {
+            warn!(
+                "RPC node stalled {} times recently, {} in total",
+                stalls - last_stalls,
+                stalls,
+            );
+            last_stalls = stalls;
+        }
+        let invalid_templates = rpc_info.invalid_templates.load(atomic::Ordering::Relaxed);
+        if invalid_templates != last_invalid_templates {
+            warn!(
+                "rejected {} invalid block templates recently, {} in total",
+                invalid_templates - last_invalid_templates,
+                invalid_templates,
+            );
+            last_invalid_templates = invalid_templates;
+        }
+        let discarded_batches = rpc_info.discarded_batches.load(atomic::Ordering::Relaxed);
+        if discarded_batches != last_discarded_batches {
+            info!(
+                "discarded {} hash batches from an old RandomX key recently, {} in total",
+                discarded_batches - last_discarded_batches,
+                discarded_batches,
+            );
+            last_discarded_batches = discarded_batches;
+        }
+        let stale_hashes = rpc_info.stale_hashes.load(atomic::Ordering::Relaxed);
+        if stale_hashes != last_stale_hashes {
+            info!(
+                "discarded {} hashes of stale work recently, {} in total",
+                stale_hashes - last_stale_hashes,
+                stale_hashes,
+            );
+            last_stale_hashes = stale_hashes;
+        }
     }
 }
 
-pub fn start(rpc_info: Arc<RpcInfo>) {
-    std::thread::spawn(|| run(rpc_info));
+pub fn start(supervisor: &mut Supervisor, rpc_info: Arc<RpcInfo>) {
+    supervisor.spawn("info", move || run(rpc_info.clone()));
 }
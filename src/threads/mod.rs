@@ -1,12 +1,21 @@
-use crate::cli::Opts;
-use crossbeam_channel::bounded;
-use std::thread::JoinHandle;
+use crate::{cli::Opts, placement::Placement, rpc::RpcClientError};
+use balancer::Balance;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use eyre::{eyre, Report};
+use rpc_manager::RpcInfo;
+use std::{fmt, sync::Arc, thread::JoinHandle, time::Instant};
+use supervisor::{Backoff, Supervised, Supervisor};
 
-mod first_hasher;
+pub(crate) mod balancer;
+pub mod bench;
+mod hasher;
 mod info;
+pub(crate) mod nonces;
+pub(crate) mod pause;
+mod rekey;
 mod rpc_manager;
-mod second_hasher;
 mod signer;
+pub(crate) mod supervisor;
 
 const HASH_CHAN_BATCH_SIZE: usize = 64;
 const HASH_CHAN_CAPACITY: usize = 2;
@@ -14,35 +23,171 @@ const HASH_CHAN_CAPACITY: usize = 2;
 pub struct PartialHashBatch<T> {
     pub seq: usize,
     pub height: usize,
+    /// The `key_epoch` of the template, so both hashes of a block are with the same key.
+    pub key_epoch: usize,
     pub items: [(u32, T); HASH_CHAN_BATCH_SIZE],
 }
 
-pub fn start(opts: Opts) -> JoinHandle<()> {
-    if opts.bls_threads == 0 || opts.randomx_threads == 0 || opts.randomx_init_threads == 0 {
-        eprintln!("You must specify a positive number of each thread type");
-        std::process::exit(1);
+/// Spawns a thread which drops `exited` when it stops, however it stops.
+fn spawn_tracked<T, F>(exited: Sender<()>, f: F) -> JoinHandle<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let _exited = exited;
+        f()
+    })
+}
+
+/// Why shutting down wasn't clean.
+#[derive(Debug)]
+pub enum ShutdownError {
+    /// The RPC thread gave up on the node, before or during shutdown.
+    Rpc(RpcClientError),
+    /// A thread panicked while stopping.
+    Panicked,
+    /// A worker thread kept failing after being restarted, so we stopped mining.
+    GaveUp(&'static str),
+    /// Threads were still going at the deadline, so they were left behind.
+    TimedOut,
+}
+
+impl fmt::Display for ShutdownError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownError::Rpc(err) => write!(f, "gave up on RPC: {}", err),
+            ShutdownError::Panicked => write!(f, "a thread panicked while stopping"),
+            ShutdownError::GaveUp(name) => write!(f, "{} thread kept failing", name),
+            ShutdownError::TimedOut => write!(f, "timed out waiting for threads to stop"),
+        }
     }
-    if opts.randomx_threads % 2 != 0 {
-        eprintln!(
-            "You must specify an even number of RandomX threads ({} specified)",
-            opts.randomx_threads,
-        );
-        std::process::exit(1);
+}
+
+impl std::error::Error for ShutdownError {}
+
+/// The running miner. Dropping it stops mining too, without waiting for anything.
+pub struct Miner {
+    rpc_thread: rpc_manager::RpcThread,
+    rpc_exited: Receiver<()>,
+    stop_hashers: Sender<()>,
+    stop_rpc: Sender<Instant>,
+    workers: Supervised,
+}
+
+impl Miner {
+    /// Disconnected once the RPC thread stops, which it only does by itself if we give up on the node.
+    pub fn rpc_exited(&self) -> &Receiver<()> {
+        &self.rpc_exited
     }
-    let (rpc_info, handle) = rpc_manager::start(opts.clone());
-    let (first_input, first_output) = bounded(HASH_CHAN_CAPACITY);
-    for _ in 0..(opts.randomx_threads / 2) {
-        first_hasher::start(rpc_info.clone(), first_input.clone());
+
+    /// Disconnected once a worker thread kept failing, and we gave up on restarting it.
+    pub fn gave_up(&self) -> &Receiver<()> {
+        self.workers.gave_up()
+    }
+
+    /// Stops the hashers, publishes the blocks they found, then joins every thread.
+    /// Whatever's still going at `deadline` is left behind, though found blocks are in the journal.
+    pub fn shutdown(self, deadline: Instant) -> Result<(), ShutdownError> {
+        drop(self.stop_hashers);
+        let workers = self.workers.join(deadline);
+        // The hashers are gone, so every block they found is queued for the RPC thread
+        let _ = self.stop_rpc.send(deadline);
+        if let Err(RecvTimeoutError::Timeout) = self.rpc_exited.recv_deadline(deadline) {
+            return Err(ShutdownError::TimedOut);
+        }
+        match self.rpc_thread.join() {
+            Ok(Ok(())) => workers,
+            Ok(Err(err)) => Err(ShutdownError::Rpc(err)),
+            Err(_) => Err(ShutdownError::Panicked),
+        }
     }
+}
+
+/// Starts the signers and RandomX threads, fed by `rpc_info`.
+fn start_workers(supervisor: &mut Supervisor, opts: &Opts, rpc_info: &Arc<RpcInfo>) {
+    let (first_input, first_output) = bounded(HASH_CHAN_CAPACITY);
     let (second_input, second_output) = bounded(HASH_CHAN_CAPACITY);
-    for _ in 0..opts.bls_threads {
-        signer::start(rpc_info.clone(), first_output.clone(), second_input.clone());
+    if opts.auto_balance {
+        let total = opts.bls_threads + opts.randomx_threads;
+        let balance = Arc::new(Balance::new(opts.bls_threads, total));
+        for i in 0..total {
+            balancer::start_thread(
+                supervisor,
+                rpc_info.clone(),
+                balance.assign(i),
+                first_input.clone(),
+                first_output.clone(),
+                second_input.clone(),
+                second_output.clone(),
+            );
+        }
+        balancer::start(
+            supervisor,
+            rpc_info.clone(),
+            balance,
+            first_output,
+            second_output,
+        );
+    } else {
+        for i in 0..opts.bls_threads {
+            signer::start(
+                supervisor,
+                rpc_info.clone(),
+                i,
+                first_output.clone(),
+                second_input.clone(),
+            );
+        }
+        for i in 0..opts.randomx_threads {
+            hasher::start(
+                supervisor,
+                rpc_info.clone(),
+                i,
+                first_input.clone(),
+                second_output.clone(),
+            );
+        }
     }
-    for _ in 0..(opts.randomx_threads / 2) {
-        second_hasher::start(rpc_info.clone(), second_output.clone());
+}
+
+/// Checks there's a thread of each type, though light mode never initializes a dataset.
+fn check_thread_counts(opts: &Opts) -> Result<(), Report> {
+    let init_threads_needed = opts.get_randomx_flags().get_full_mem();
+    if opts.bls_threads == 0
+        || opts.randomx_threads == 0
+        || (init_threads_needed && opts.randomx_init_threads == 0)
+    {
+        return Err(eyre!(
+            "You must specify a positive number of each thread type"
+        ));
     }
+    Ok(())
+}
+
+pub fn start(opts: Opts) -> Result<Miner, Report> {
+    check_thread_counts(&opts)?;
+    let (stop_hashers, hashers_stop) = bounded(0);
+    let (stop_rpc, rpc_stop) = bounded(1);
+    let (rpc_exited_send, rpc_exited) = bounded(0);
+    let placement = Arc::new(Placement::new(&opts)?);
+    let (rpc_info, rpc_thread) = rpc_manager::start(
+        opts.clone(),
+        placement,
+        hashers_stop,
+        rpc_stop,
+        rpc_exited_send,
+    )?;
+    let mut supervisor = Supervisor::new(Backoff::default(), rpc_info.stop.clone());
+    start_workers(&mut supervisor, &opts, &rpc_info);
     if opts.output_hash_rate {
-        info::start(rpc_info);
+        info::start(&mut supervisor, rpc_info);
     }
-    handle
+    Ok(Miner {
+        rpc_thread,
+        rpc_exited,
+        stop_hashers,
+        stop_rpc,
+        workers: supervisor.start(),
+    })
 }
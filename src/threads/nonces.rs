@@ -0,0 +1,40 @@
+//! Splitting a template's nonces between the RandomX threads, a batch at a time.
+//!
+//! Batches are claimed in order from a random starting point, so no two threads hash the same
+//! nonce, and running out of them is noticed. With a fixed seed, the starting points repeat.
+
+use super::{rpc_manager::Nonce, HASH_CHAN_BATCH_SIZE};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many batches it takes to cover every nonce.
+pub const BATCHES: usize = ((1u64 << 32) / HASH_CHAN_BATCH_SIZE as u64) as usize;
+
+pub struct Nonces {
+    start: Nonce,
+    claimed: AtomicUsize,
+}
+
+impl Nonces {
+    pub fn new(start: Nonce) -> Nonces {
+        Nonces {
+            start,
+            claimed: AtomicUsize::new(0),
+        }
+    }
+
+    /// The first nonce of the next batch, or None once every batch was claimed.
+    pub fn claim(&self) -> Option<Nonce> {
+        let batch = self.claimed.fetch_add(1, Ordering::Relaxed);
+        if batch >= BATCHES {
+            // Keep the count from wrapping around to batches claimed long ago
+            self.claimed.store(BATCHES, Ordering::Relaxed);
+            return None;
+        }
+        let offset = (batch as Nonce).wrapping_mul(HASH_CHAN_BATCH_SIZE as Nonce);
+        Some(self.start.wrapping_add(offset))
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.claimed.load(Ordering::Relaxed) >= BATCHES
+    }
+}
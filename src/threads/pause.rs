@@ -0,0 +1,135 @@
+//! Pausing the hashing threads, so the RandomX cache can be rekeyed in place.
+//!
+//! Workers check for a pause between batches, and wake up for one when blocked on a channel.
+//! Parking means letting go of the template and cache first, so once every worker is parked,
+//! nothing else holds them.
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use parking_lot::{Condvar, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+struct State {
+    requested: bool,
+    workers: usize,
+    parked: usize,
+}
+
+pub struct Pause {
+    state: Mutex<State>,
+    changed: Condvar,
+    /// Mirrors `state.requested`, for workers to check cheaply.
+    requested: AtomicBool,
+    /// One message per worker when a pause starts, to wake up those blocked on other channels.
+    wake_send: Sender<()>,
+    wake_recv: Receiver<()>,
+}
+
+impl Pause {
+    pub fn new() -> Pause {
+        let (wake_send, wake_recv) = unbounded();
+        Pause {
+            state: Mutex::new(State {
+                requested: false,
+                workers: 0,
+                parked: 0,
+            }),
+            changed: Condvar::new(),
+            requested: AtomicBool::new(false),
+            wake_send,
+            wake_recv,
+        }
+    }
+
+    /// Adds a worker which must park before a pause takes effect, until the handle is dropped.
+    pub fn register(self: &Arc<Self>) -> Worker {
+        let mut state = self.state.lock();
+        state.workers += 1;
+        if state.requested {
+            let _ = self.wake_send.send(());
+        }
+        Worker {
+            pause: self.clone(),
+        }
+    }
+
+    /// Asks every worker to park, and blocks until they all have.
+    /// They're released once the returned guard is dropped.
+    pub fn pause(&self) -> Paused<'_> {
+        let mut state = self.state.lock();
+        state.requested = true;
+        self.requested.store(true, Ordering::SeqCst);
+        for _ in 0..state.workers {
+            let _ = self.wake_send.send(());
+        }
+        while state.parked < state.workers {
+            self.changed.wait(&mut state);
+        }
+        Paused { pause: self }
+    }
+
+    fn resume(&self) {
+        let mut state = self.state.lock();
+        state.requested = false;
+        self.requested.store(false, Ordering::SeqCst);
+        // Workers which parked by themselves never took theirs
+        while self.wake_recv.try_recv().is_ok() {}
+        drop(state);
+        self.changed.notify_all();
+    }
+}
+
+impl Default for Pause {
+    fn default() -> Pause {
+        Pause::new()
+    }
+}
+
+/// Every worker is parked for as long as this is held.
+pub struct Paused<'a> {
+    pause: &'a Pause,
+}
+
+impl Drop for Paused<'_> {
+    fn drop(&mut self) {
+        self.pause.resume();
+    }
+}
+
+/// A worker's side of the protocol.
+pub struct Worker {
+    pause: Arc<Pause>,
+}
+
+impl Worker {
+    pub fn requested(&self) -> bool {
+        self.pause.requested.load(Ordering::SeqCst)
+    }
+
+    /// Receives a message when a pause starts, to select on alongside blocking channel operations.
+    /// It may also receive one late, so check `requested` before parking.
+    pub fn wake(&self) -> &Receiver<()> {
+        &self.pause.wake_recv
+    }
+
+    /// Blocks until the pause is over.
+    /// Anything the manager needs to itself, like the RandomX cache, must be dropped first.
+    pub fn park(&self) {
+        let mut state = self.pause.state.lock();
+        state.parked += 1;
+        self.pause.changed.notify_all();
+        while state.requested {
+            self.pause.changed.wait(&mut state);
+        }
+        state.parked -= 1;
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.pause.state.lock().workers -= 1;
+        self.pause.changed.notify_all();
+    }
+}
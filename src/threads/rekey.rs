@@ -0,0 +1,173 @@
+//! Initializing RandomX for a new key on its own thread.
+//! It takes many seconds, and the RPC thread has templates to poll and blocks to publish meanwhile.
+//!
+//! Keys change on a schedule, so the next one can usually be initialized well before it's needed.
+
+use super::spawn_tracked;
+use crate::placement::Placement;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use log::{error, info, warn};
+use randomx::{Cache, Flags};
+use std::{sync::Arc, thread::JoinHandle, time::Instant};
+
+/// Meros switches keys every `KEY_EPOCH` blocks, to the hash of the last block before the switch.
+const KEY_EPOCH: usize = 384;
+/// How many blocks after the epoch starts the new key is used, so it's known ahead of time.
+const KEY_DELAY: usize = 12;
+
+pub type Key = [u8; 32];
+/// A key, and its cache for each NUMA node.
+pub type Rekeyed = (Key, Result<Vec<Arc<Cache>>, randomx::Error>);
+
+/// With `height` blocks in the chain, the height of the block whose hash is the next key,
+/// if it's already in the chain but not yet in use.
+pub fn upcoming_key_block(height: usize) -> Option<usize> {
+    let into_epoch = height % KEY_EPOCH;
+    if height < KEY_EPOCH || into_epoch >= KEY_DELAY {
+        return None;
+    }
+    Some(height - into_epoch - 1)
+}
+
+fn run(
+    placement: Arc<Placement>,
+    flags: Flags,
+    requests: Receiver<(Key, u64)>,
+    results: Sender<Rekeyed>,
+) {
+    let mut queued = None;
+    loop {
+        let mut request = match queued.take() {
+            Some(request) => request,
+            None => match requests.recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            },
+        };
+        // Only the latest key is of any use
+        while let Ok(newer) = requests.try_recv() {
+            request = newer;
+        }
+        let (key, threads) = request;
+        info!(
+            "initializing RandomX for key {} with {} threads..",
+            hex::encode_upper(key),
+            threads,
+        );
+        let caches = placement.new_caches(flags, &key, threads);
+        let done = caches.is_ok();
+        if done {
+            info!("initialized RandomX for key {}", hex::encode_upper(key));
+        }
+        // Asking for this key again meanwhile was only to get it sooner, with more threads
+        queued = requests
+            .try_iter()
+            .last()
+            .filter(|&(k, _)| !done || k != key);
+        if results.send((key, caches)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Hands out caches for new keys once they're ready, starting on them as they're asked for.
+pub struct Rekeyer {
+    requests: Sender<(Key, u64)>,
+    init_threads: u64,
+    precompute_threads: u64,
+    results: Receiver<Rekeyed>,
+    /// The key on its way, and how many threads it was asked for with.
+    pending: Option<(Key, u64)>,
+    ready: Option<(Key, Vec<Arc<Cache>>)>,
+    worker: JoinHandle<()>,
+    worker_exited: Receiver<()>,
+}
+
+impl Rekeyer {
+    /// Keys needed right away get `init_threads`, and those prepared ahead of time
+    /// get `precompute_threads`, to leave the rest to mining.
+    pub fn start(
+        placement: Arc<Placement>,
+        flags: Flags,
+        init_threads: u64,
+        precompute_threads: u64,
+    ) -> Rekeyer {
+        let (requests, requests_recv) = unbounded();
+        let (results_send, results) = unbounded();
+        let (exited, worker_exited) = unbounded();
+        let worker = spawn_tracked(exited, move || {
+            run(placement, flags, requests_recv, results_send)
+        });
+        Rekeyer {
+            requests,
+            init_threads,
+            precompute_threads,
+            results,
+            pending: None,
+            ready: None,
+            worker,
+            worker_exited,
+        }
+    }
+
+    /// Stops the worker, waiting for it to finish the key it's on, if any, until `deadline`.
+    /// Past that, it's left behind.
+    pub fn stop(self, deadline: Instant) {
+        drop(self.requests);
+        if let Err(RecvTimeoutError::Timeout) = self.worker_exited.recv_deadline(deadline) {
+            warn!("left RandomX initializing for a new key behind");
+            return;
+        }
+        let _ = self.worker.join();
+    }
+
+    /// Receives finished caches, to pass to `finished`.
+    pub fn results(&self) -> &Receiver<Rekeyed> {
+        &self.results
+    }
+
+    pub fn finished(&mut self, (key, caches): Rekeyed) {
+        if self.pending.map(|(k, _)| k) != Some(key) {
+            // Asked for a different key since, which is still on its way
+            return;
+        }
+        self.pending = None;
+        match caches {
+            Ok(caches) => self.ready = Some((key, caches)),
+            Err(err) => error!("failed to initialize RandomX for new key: {}", err),
+        }
+    }
+
+    /// Asks for `key` with `threads`, unless it's already on its way with at least as many.
+    fn request(&mut self, key: &Key, threads: u64) {
+        match self.pending {
+            Some((k, pending_threads)) if k == *key && pending_threads >= threads => {}
+            _ => {
+                self.pending = Some((*key, threads));
+                // The worker only exits once we're stopped or dropped
+                let _ = self.requests.send((*key, threads));
+            }
+        }
+    }
+
+    /// Starts on the cache for a key which will be needed soon, unless it's already ready.
+    /// Does nothing with 0 precompute threads.
+    pub fn prepare(&mut self, key: &Key) {
+        let ready = self.ready.as_ref().map(|(k, _)| k) == Some(key);
+        if self.precompute_threads > 0 && !ready {
+            self.request(key, self.precompute_threads);
+        }
+    }
+
+    /// Takes the cache for `key` if it's ready, and otherwise makes sure it's on its way.
+    /// A precompute for it the worker hasn't started on yet gets `init_threads` instead,
+    /// as the key is needed now.
+    pub fn take(&mut self, key: &Key) -> Option<Vec<Arc<Cache>>> {
+        match self.ready.take() {
+            Some((ready_key, caches)) if ready_key == *key => return Some(caches),
+            _ => {}
+        }
+        self.request(key, self.init_threads);
+        None
+    }
+}
@@ -1,13 +1,24 @@
+use super::{
+    nonces::Nonces,
+    pause::Pause,
+    rekey::{self, Rekeyer},
+    spawn_tracked,
+};
 use crate::{
     bls::{SecretKey, SIG_SIZE},
     cli::Opts,
-    rpc::Rpc,
+    journal::{self, Event, FoundBlock, Journal},
+    placement::Placement,
+    rpc::{RequestId, Rpc, RpcClientError},
+    template,
+    utils::difficulty_to_max_hash,
 };
-use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use eyre::{Report, WrapErr};
 use hashbrown::HashMap;
-use log::{debug, info, trace, warn};
-use meroxidizer::utils::difficulty_to_max_hash;
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use randomx::{Cache, HASH_SIZE};
 use std::{
     collections::VecDeque,
@@ -16,17 +27,23 @@ use std::{
         Arc,
     },
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub type Nonce = u32;
+/// A block found by the hashers: (seq, nonce, signature, hash)
+pub type Found = (usize, Nonce, [u8; SIG_SIZE], [u8; HASH_SIZE]);
 
 pub struct BlockTemplate {
     pub seq: usize,
     pub header: Vec<u8>,
-    pub randomx_cache: Arc<Cache>,
+    /// One for each NUMA node in use, by its index in the placement.
+    pub randomx_caches: Vec<Arc<Cache>>,
     pub max_hash: [u8; 32],
     pub height: usize,
+    /// Counts RandomX key changes, as the cache may be rekeyed in place.
+    pub key_epoch: usize,
+    pub nonces: Nonces,
     id: i64,
 }
 
@@ -34,24 +51,252 @@ pub struct RpcInfo {
     pub miner_key: SecretKey,
     pub latest_template: RwLock<Arc<BlockTemplate>>,
     pub latest_seq: AtomicUsize,
-    /// Channel of (seq, nonce)
-    pub publish_channel: Sender<(usize, Nonce, [u8; SIG_SIZE], [u8; HASH_SIZE])>,
+    pub latest_height: AtomicUsize,
+    pub publish_channel: Sender<Found>,
     /// Measured in units of `HASH_BATCH_SIZE`
     pub num_hashes_rec: AtomicUsize,
+    /// How many times the RPC node stalled and had to be reconnected to, in total.
+    pub rpc_stalls: AtomicUsize,
+    /// How many distinct block templates were rejected as invalid, in total.
+    pub invalid_templates: AtomicUsize,
+    /// How many hash batches reached their second hash after a key change, and were discarded.
+    pub discarded_batches: AtomicUsize,
+    /// How many hashes went into batches which were dropped or cut short for being stale, in total.
+    pub stale_hashes: AtomicUsize,
+    /// For the hashers to let go of the RandomX cache, so it can be rekeyed in place.
+    pub pause: Arc<Pause>,
+    pub placement: Arc<Placement>,
+    /// Disconnected once the hashers should stop, to shut down.
+    pub stop: Receiver<()>,
+    /// Takes the seq of a template whose nonces were all claimed, to get a fresh one sooner.
+    pub exhausted: Sender<usize>,
+}
+
+impl RpcInfo {
+    /// If blocks found on template `seq` would be thrown away by now, as it's no longer retained.
+    pub fn is_stale(&self, seq: usize, height: usize) -> bool {
+        height < self.latest_height.load(atomic::Ordering::Relaxed)
+            || seq + RETAIN_SEQS <= self.latest_seq.load(atomic::Ordering::Relaxed)
+    }
 }
 
 const GET_TEMPLATE_INTERVAL: Duration = Duration::from_secs(1);
+/// Even when the node notifies us of changes, poll occasionally in case one gets lost.
+const SUBSCRIBED_TEMPLATE_INTERVAL: Duration = Duration::from_secs(30);
 const RETAIN_SEQS: usize = 5;
+/// How often to check in on publishes while shutting down, with nothing else to wait on.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A found block on its way to the node.
+struct Publish {
+    block: FoundBlock,
+    /// The request publishing it, or None if it still has to be (re)sent.
+    request: Option<RequestId>,
+}
+
+fn record(journal: &mut Journal, event: &Event) {
+    if let Err(err) = journal.append(event) {
+        error!("failed to write to found block journal: {}", err);
+    }
+}
+
+/// Sends or checks in on a publish, returning its outcome once there is one.
+/// A publish which failed along with the connection is sent again after reconnecting.
+fn check_publish(rpc: &mut Rpc, publish: &mut Publish) -> Option<Event> {
+    let res = match publish.request {
+        Some(id) => rpc.poll_response::<bool>(id),
+        None => {
+            let block = &publish.block;
+            let params = (block.template_id, hex::encode_upper(block.contents()));
+            debug!("attempting to publish block with params {:?}", params);
+            let request = &mut publish.request;
+            rpc.start_request("merit_publishBlock", params).map(|id| {
+                *request = Some(id);
+                None
+            })
+        }
+    };
+    let hash = publish.block.hash.clone();
+    match res {
+        Ok(None) => None,
+        Ok(Some(true)) => {
+            debug!("successfully published block :)");
+            Some(Event::Published { hash })
+        }
+        Ok(Some(false)) => {
+            warn!("failed to publish block for unknown reason :(");
+            Some(Event::Rejected {
+                hash,
+                reason: "unknown reason".to_owned(),
+            })
+        }
+        Err(RpcClientError::Rpc(err)) => {
+            warn!("failed to publish block :( error: {}", err);
+            Some(Event::Rejected {
+                hash,
+                reason: err.to_string(),
+            })
+        }
+        Err(err) => {
+            warn!(
+                "failed to publish block, retrying once reconnected: {}",
+                err
+            );
+            publish.request = None;
+            None
+        }
+    }
+}
+
+/// Moves publishes along now that the chain is at `height`,
+/// giving up on those for templates the chain has moved past.
+fn publish_blocks(
+    rpc: &mut Rpc,
+    journal: &mut Journal,
+    publishes: &mut Vec<Publish>,
+    height: usize,
+) {
+    let mut i = 0;
+    while i < publishes.len() {
+        let publish = &mut publishes[i];
+        // One in flight may well have been what moved the chain on, so wait to hear back
+        let outcome = if publish.request.is_none() && publish.block.height < height {
+            warn!(
+                "chain moved on before block {} was published :(",
+                publish.block.hash_hex(),
+            );
+            Some(Event::Expired {
+                hash: publish.block.hash.clone(),
+            })
+        } else {
+            check_publish(rpc, publish)
+        };
+        match outcome {
+            Some(event) => {
+                record(journal, &event);
+                publishes.remove(i);
+            }
+            None => i += 1,
+        }
+    }
+}
 
-pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
-    let mut rpc = Rpc::connect(opts.clone());
+/// Journals a block the hashers found, and returns it to publish, unless its seq has expired.
+fn journal_found(
+    journal: &mut Journal,
+    seqs_to_templates: &HashMap<usize, Arc<BlockTemplate>>,
+    (seq, nonce, signature, hash): Found,
+) -> Option<Publish> {
+    let template = match seqs_to_templates.get(&seq) {
+        Some(template) => template,
+        None => {
+            warn!("found block with expired seq :(");
+            return None;
+        }
+    };
+    info!("found block! hash: {}", hex::encode_upper(hash));
+    let block = FoundBlock {
+        template_id: template.id,
+        header: template.header.clone(),
+        nonce,
+        // TODO: remove casts once we update our minimum rust version enough
+        signature: (&signature as &[u8]).to_vec(),
+        hash: (&hash as &[u8]).to_vec(),
+        seq,
+        height: template.height,
+        time: journal::now(),
+    };
+    record(journal, &Event::Found(block.clone()));
+    Some(Publish {
+        block,
+        request: None,
+    })
+}
+
+/// Publishes until every block is published or given up on, or until `deadline`.
+fn finish_publishing(
+    rpc: &mut Rpc,
+    journal: &mut Journal,
+    publishes: &mut Vec<Publish>,
+    height: usize,
+    deadline: Instant,
+) {
+    loop {
+        publish_blocks(rpc, journal, publishes, height);
+        if publishes.is_empty() {
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "shutting down with {} blocks unpublished, resubmit them from the journal",
+                publishes.len(),
+            );
+            return;
+        }
+        std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+}
+
+/// What the workers need to mine `header` without a node, with a target no hash meets,
+/// so nothing is ever published. They stop once `stop` disconnects.
+pub(super) fn offline(
+    placement: Arc<Placement>,
+    miner_key: SecretKey,
+    header: Vec<u8>,
+    randomx_caches: Vec<Arc<Cache>>,
+    stop: Receiver<()>,
+) -> Arc<RpcInfo> {
+    let template = BlockTemplate {
+        seq: 0,
+        header,
+        randomx_caches,
+        max_hash: [0; 32],
+        height: 0,
+        key_epoch: 0,
+        nonces: Nonces::new(0),
+        id: 0,
+    };
+    // Nothing's found, and the nonces outlast any run, so nobody listens
+    let (publish_channel, _) = bounded(0);
+    let (exhausted, _) = bounded(1);
+    Arc::new(RpcInfo {
+        miner_key,
+        latest_template: RwLock::new(Arc::new(template)),
+        latest_seq: AtomicUsize::new(0),
+        latest_height: AtomicUsize::new(0),
+        publish_channel,
+        num_hashes_rec: AtomicUsize::new(0),
+        rpc_stalls: AtomicUsize::new(0),
+        invalid_templates: AtomicUsize::new(0),
+        discarded_batches: AtomicUsize::new(0),
+        stale_hashes: AtomicUsize::new(0),
+        pause: Arc::new(Pause::new()),
+        placement,
+        stop,
+        exhausted,
+    })
+}
+
+/// Only returns an error once the RPC retry policy gives up.
+pub type RpcThread = JoinHandle<Result<(), RpcClientError>>;
+
+/// The RPC thread stops once `shutdown` receives a deadline to publish found blocks by,
+/// which should be after the hashers stopped on `stop`. It drops `exited` on the way out.
+pub fn start(
+    opts: Opts,
+    placement: Arc<Placement>,
+    stop: Receiver<()>,
+    shutdown: Receiver<Instant>,
+    exited: Sender<()>,
+) -> Result<(Arc<RpcInfo>, RpcThread), Report> {
+    let mut rpc = Rpc::connect(opts.clone()).wrap_err("Failed to connect to RPC")?;
     let miner_key = match std::env::var("MEROS_MINER_KEY") {
         Ok(s) => hex::decode(s).expect("Failed to decode MEROS_MINER_KEY env var"),
         Err(std::env::VarError::NotPresent) => {
-            match rpc.single_request::<_, String>("personal_getMiner", [(); 0]) {
-                Ok(miner) => hex::decode(&miner).expect("Failed to decode miner key from RPC"),
-                Err(err) => panic!("Failed to get miner key from RPC: {}", err),
-            }
+            let miner = rpc
+                .single_request::<_, String>("personal_getMiner", [(); 0])
+                .wrap_err("Failed to get miner key from RPC")?;
+            hex::decode(&miner).wrap_err("Failed to decode miner key from RPC")?
         }
         Err(err) => {
             panic!(
@@ -61,28 +306,52 @@ pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
         }
     };
     let miner_key = SecretKey::new(&miner_key).expect("Invalid miner key specified");
+    let miner_pubkey_bytes = miner_key.get_public_key();
     // TODO: remove reference and cast once we update our minimum rust version enough
-    let miner_pubkey = hex::encode_upper(&miner_key.get_public_key() as &[u8]);
-    let height = rpc.get_height();
-    let target = rpc.get_mining_target(&miner_pubkey);
+    let miner_pubkey = hex::encode_upper(&miner_pubkey_bytes as &[u8]);
+    let (height, target) = rpc
+        .poll_template(&miner_pubkey)
+        .wrap_err("Failed to get block template from RPC")?;
+    template::validate(&target, &miner_pubkey_bytes)
+        .wrap_err_with(|| format!("RPC node {} sent an invalid block template", rpc.node()))?;
     info!("loaded miner public key {}", miner_pubkey);
+    if rpc.subscribe() {
+        info!("subscribed to template updates from the node");
+    } else {
+        info!("node doesn't support subscriptions, polling for template updates");
+    }
+    let notifications = rpc.notifications();
 
     info!("initializing RandomX..");
-    let cache = Cache::new(
-        opts.get_randomx_flags(),
-        &target.key,
-        opts.randomx_init_threads,
-    )
-    .unwrap();
+    let caches = placement
+        .new_caches(
+            opts.get_randomx_flags(),
+            &target.key,
+            opts.randomx_init_threads,
+        )
+        .unwrap();
     info!("initialized RandomX");
 
+    let mut journal = Journal::open(&opts.journal).wrap_err_with(|| {
+        format!(
+            "Failed to open found block journal {}",
+            opts.journal.display()
+        )
+    })?;
     let (publish_send, publish_recv) = bounded(64);
+    let (exhausted_send, exhausted) = bounded(1);
+    let mut nonce_rng = match opts.nonce_seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
     let mut last_template = Arc::new(BlockTemplate {
         seq: 0,
         header: target.header,
-        randomx_cache: Arc::new(cache),
+        randomx_caches: caches,
         max_hash: difficulty_to_max_hash(target.difficulty),
         height,
+        key_epoch: 0,
+        nonces: Nonces::new(nonce_rng.gen()),
         id: target.id,
     });
     debug!(
@@ -95,8 +364,17 @@ pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
         miner_key,
         latest_template: RwLock::new(last_template.clone()),
         latest_seq: AtomicUsize::new(0),
+        latest_height: AtomicUsize::new(height),
         publish_channel: publish_send,
         num_hashes_rec: AtomicUsize::new(0),
+        rpc_stalls: AtomicUsize::new(rpc.stalls()),
+        invalid_templates: AtomicUsize::new(0),
+        discarded_batches: AtomicUsize::new(0),
+        stale_hashes: AtomicUsize::new(0),
+        pause: Arc::new(Pause::new()),
+        placement: placement.clone(),
+        stop,
+        exhausted: exhausted_send,
     });
 
     let mut recent_seqs = VecDeque::new();
@@ -105,47 +383,164 @@ pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
     let mut seqs_to_templates = HashMap::new();
     seqs_to_templates.insert(0, last_template.clone());
     let mut last_randomx_key = target.key;
+    let mut key_epoch = 0;
+    let mut rekeyer = Rekeyer::start(
+        placement,
+        opts.get_randomx_flags(),
+        opts.randomx_init_threads,
+        opts.randomx_precompute_threads,
+    );
+    let mut upcoming_key_block = None;
+    let rekeyed = rekeyer.results().clone();
+    let mut publishes = Vec::new();
+    let mut last_rejected_id = None;
     let rpc_info2 = rpc_info.clone();
-    let background = std::thread::spawn(move || loop {
-        match publish_recv.recv_timeout(GET_TEMPLATE_INTERVAL) {
-            Ok((seq, nonce, signature, hash)) => {
-                if let Some(template) = seqs_to_templates.get(&seq) {
-                    info!("found block! hash: {}", hex::encode_upper(hash));
-                    let mut contents = template.header.clone();
-                    contents.extend(&nonce.to_le_bytes());
-                    // TODO: remove cast once we update our minimum rust version enough
-                    contents.extend(&signature as &[u8]);
-                    let params = (template.id, hex::encode_upper(contents));
-                    debug!("attempting to publish block with params {:?}", params);
-                    let res: Result<bool, _> = rpc.single_request("merit_publishBlock", params);
-                    match res {
-                        Ok(true) => debug!("successfully published block :)"),
-                        Ok(false) => warn!("failed to publish block for unknown reason :("),
-                        Err(err) => warn!("failed to publish block :( error: {}", err),
-                    }
-                    // Empty publish channel as previous blocks aren't useful
-                    while publish_recv.try_recv().is_ok() {}
-                } else {
-                    warn!("found block with expired seq :(");
+    let background = spawn_tracked(exited, move || loop {
+        // Notifications won't come for a template which ran out of nonces
+        let interval = if rpc.is_subscribed() && !last_template.nonces.exhausted() {
+            SUBSCRIBED_TEMPLATE_INTERVAL
+        } else {
+            GET_TEMPLATE_INTERVAL
+        };
+        select! {
+            recv(publish_recv) -> found => match found {
+            Ok(found) => {
+                // Others may have been found meanwhile, and they're all worth keeping.
+                // Only take those already queued though, or easy enough blocks would keep us here.
+                let queued = publish_recv.try_iter().take(publish_recv.len());
+                for found in Some(found).into_iter().chain(queued) {
+                    publishes.extend(journal_found(&mut journal, &seqs_to_templates, found));
+                }
+                // Sent right away, with the template poll below waiting on the node anyways
+                publish_blocks(&mut rpc, &mut journal, &mut publishes, last_template.height);
+            }
+            Err(_) => return Ok(()),
+            },
+            recv(notifications) -> notification => {
+                if let Ok(notification) = notification {
+                    debug!("got {} notification, refreshing template", notification.method);
+                }
+                // Coalesce bursts of notifications into a single refresh
+                while notifications.try_recv().is_ok() {}
+            },
+            recv(exhausted) -> seq => {
+                if seq.ok() != Some(last_template.seq) {
                     continue;
                 }
+                info!(
+                    "tried every nonce of template seq {}, refreshing template",
+                    last_template.seq,
+                );
+            },
+            recv(rekeyed) -> rekeyed => {
+                if let Ok(rekeyed) = rekeyed {
+                    rekeyer.finished(rekeyed);
+                }
+            },
+            recv(shutdown) -> deadline => {
+                // Without a deadline, the miner was dropped, and nothing is waiting on us
+                let deadline = deadline.unwrap_or_else(|_| Instant::now());
+                info!("shutting down, publishing the blocks found so far..");
+                let queued = publish_recv.try_iter().take(publish_recv.len());
+                for found in queued {
+                    publishes.extend(journal_found(&mut journal, &seqs_to_templates, found));
+                }
+                let height = last_template.height;
+                finish_publishing(&mut rpc, &mut journal, &mut publishes, height, deadline);
+                rekeyer.stop(deadline);
+                return Ok(());
+            },
+            default(interval) => {},
+        }
+        let polled = rpc
+            .check_health()
+            .and_then(|()| rpc.poll_template(&miner_pubkey));
+        rpc_info2
+            .rpc_stalls
+            .store(rpc.stalls(), atomic::Ordering::Relaxed);
+        let (height, target) = match polled {
+            Ok(polled) => polled,
+            Err(RpcClientError::Rpc(err)) => {
+                // The node is there, just not ready to give us a template, so try again later
+                warn!("failed to get block template: {}", err);
+                continue;
+            }
+            Err(err) => {
+                error!("giving up on RPC: {}", err);
+                return Err(err);
+            }
+        };
+        // Publishing is pipelined with template polling, so check in on earlier publishes now
+        publish_blocks(&mut rpc, &mut journal, &mut publishes, height);
+        // Stopping for a rekey is to save memory, so don't hold two keys then
+        if !opts.randomx_stop_for_rekey {
+            match rekey::upcoming_key_block(height) {
+                Some(key_height) if upcoming_key_block != Some(key_height) => {
+                    match rpc.get_block_hash(key_height) {
+                        Ok(key) => {
+                            upcoming_key_block = Some(key_height);
+                            if key != last_randomx_key {
+                                info!("upcoming RandomX key is {}", hex::encode_upper(key));
+                                rekeyer.prepare(&key);
+                            }
+                        }
+                        // Try again next time around
+                        Err(err) => warn!("failed to get upcoming RandomX key: {}", err),
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Err(err) = template::validate(&target, &miner_pubkey_bytes) {
+            // Keep mining the last valid template, and only complain once about each bad one
+            if last_rejected_id != Some(target.id) {
+                error!(
+                    "rejecting block template {} from RPC node {}: {}",
+                    target.id,
+                    rpc.node(),
+                    err,
+                );
+                rpc_info2
+                    .invalid_templates
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                last_rejected_id = Some(target.id);
+            }
+            continue;
+        }
+        let max_hash = difficulty_to_max_hash(target.difficulty);
+        // A new seq would needlessly expire the blocks being found on the current one
+        if height == last_template.height
+            && target.id == last_template.id
+            && target.header == last_template.header
+            && max_hash == last_template.max_hash
+            && target.key == last_randomx_key
+        {
+            continue;
+        }
+        let mut randomx_caches = last_template.randomx_caches.clone();
+        if target.key != last_randomx_key && !opts.randomx_stop_for_rekey {
+            // Keep mining and publishing on the current template until the new key is ready
+            match rekeyer.take(&target.key) {
+                Some(caches) => randomx_caches = caches,
+                None => continue,
             }
-            Err(RecvTimeoutError::Timeout) => {}
-            Err(RecvTimeoutError::Disconnected) => return,
         }
-        let height = rpc.get_height();
         if height > last_template.height {
             recent_seqs.clear();
             seqs_to_templates.clear();
         }
-        let target = rpc.get_mining_target(&miner_pubkey);
+        if target.key != last_randomx_key {
+            key_epoch += 1;
+        }
         last_seq += 1;
         let mut template = BlockTemplate {
             seq: last_seq,
             header: target.header,
-            randomx_cache: last_template.randomx_cache.clone(),
-            max_hash: difficulty_to_max_hash(target.difficulty),
+            randomx_caches,
+            max_hash,
             height,
+            key_epoch,
+            nonces: Nonces::new(nonce_rng.gen()),
             id: target.id,
         };
         debug!(
@@ -154,69 +549,50 @@ pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
             template.id,
             hex::encode_upper(&template.header),
         );
-        if target.key != last_randomx_key {
+        if target.key != last_randomx_key && opts.randomx_stop_for_rekey {
             last_randomx_key = target.key;
-            if opts.randomx_stop_for_rekey {
-                let mut template_lock = rpc_info2.latest_template.write();
-                rpc_info2
-                    .latest_seq
-                    .store(template.seq, atomic::Ordering::Relaxed);
-                info!("new RandomX key! waiting for mining threads to pause..");
-                drop(last_template);
-                drop(template.randomx_cache);
-                recent_seqs.clear();
-                seqs_to_templates.clear();
-                let mut last_counts = (0, 0);
-                loop {
-                    if let Some(cache) = Arc::get_mut(&mut template_lock)
-                        .and_then(|t| Arc::get_mut(&mut t.randomx_cache))
-                    {
-                        info!("reinitializing RandomX..");
-                        cache
-                            .set_key(&target.key, opts.randomx_init_threads)
-                            .unwrap();
-                        info!("reinitialized RandomX");
-                        break;
-                    } else {
-                        if log::log_enabled!(log::Level::Trace) {
-                            let new_counts = (
-                                Arc::strong_count(&template_lock),
-                                Arc::strong_count(&template_lock.randomx_cache),
-                            );
-                            if last_counts != new_counts {
-                                trace!("current refcounts: {} {}", new_counts.0, new_counts.1);
-                                last_counts = new_counts;
-                            }
-                        }
-                        std::thread::yield_now();
-                    }
-                }
-                template.randomx_cache = template_lock.randomx_cache.clone();
-                last_template = Arc::new(template);
-                *template_lock = last_template.clone();
-            } else {
-                info!("new key! reinitializing RandomX..");
-                template.randomx_cache = Arc::new(
-                    Cache::new(
-                        opts.get_randomx_flags(),
-                        &target.key,
-                        opts.randomx_init_threads,
-                    )
-                    .unwrap(),
-                );
-                info!("reinitialized RandomX");
-                last_template = Arc::new(template);
-                *rpc_info2.latest_template.write() = last_template.clone();
-                rpc_info2
-                    .latest_seq
-                    .store(last_template.seq, atomic::Ordering::Relaxed);
-            }
+            info!("new RandomX key! waiting for mining threads to pause..");
+            let paused = rpc_info2.pause.pause();
+            let mut template_lock = rpc_info2.latest_template.write();
+            rpc_info2
+                .latest_seq
+                .store(template.seq, atomic::Ordering::Relaxed);
+            rpc_info2
+                .latest_height
+                .store(template.height, atomic::Ordering::Relaxed);
+            drop(last_template);
+            template.randomx_caches.clear();
+            recent_seqs.clear();
+            seqs_to_templates.clear();
+            // With every hasher parked, nothing else holds the template or cache
+            let caches = &mut Arc::get_mut(&mut template_lock)
+                .expect("RandomX cache still in use with mining paused")
+                .randomx_caches;
+            info!("reinitializing RandomX..");
+            rpc_info2
+                .placement
+                .set_key(caches, &target.key, opts.randomx_init_threads)
+                .unwrap();
+            info!("reinitialized RandomX");
+            template.randomx_caches = template_lock.randomx_caches.clone();
+            last_template = Arc::new(template);
+            *template_lock = last_template.clone();
+            drop(template_lock);
+            drop(paused);
         } else {
+            if target.key != last_randomx_key {
+                info!("switching to new RandomX key");
+                last_randomx_key = target.key;
+            }
+            // Hashers pick up the cache along with the template, so they switch keys all at once
             last_template = Arc::new(template);
             *rpc_info2.latest_template.write() = last_template.clone();
             rpc_info2
                 .latest_seq
                 .store(last_template.seq, atomic::Ordering::Relaxed);
+            rpc_info2
+                .latest_height
+                .store(last_template.height, atomic::Ordering::Relaxed);
         }
         seqs_to_templates.insert(last_seq, last_template.clone());
         recent_seqs.push_back(last_seq);
@@ -225,5 +601,5 @@ pub fn start(opts: Opts) -> (Arc<RpcInfo>, JoinHandle<()>) {
         }
     });
 
-    (rpc_info, background)
+    Ok((rpc_info, background))
 }
@@ -1,36 +1,95 @@
-use super::{rpc_manager::RpcInfo, PartialHashBatch, HASH_CHAN_BATCH_SIZE};
+use super::{
+    balancer::Assignment, rpc_manager::RpcInfo, PartialHashBatch, Supervisor, HASH_CHAN_BATCH_SIZE,
+};
 use crate::bls::SIG_SIZE;
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{never, select, Receiver, Sender};
 use randomx::HASH_SIZE;
-use std::sync::Arc;
+use std::sync::{atomic, Arc};
 
-fn run(
+/// Returns None if the batch is or goes stale before it's all signed.
+pub(super) fn sign(
+    rpc_info: &RpcInfo,
+    input: &PartialHashBatch<[u8; HASH_SIZE]>,
+) -> Option<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>> {
+    let mut out = PartialHashBatch {
+        seq: input.seq,
+        height: input.height,
+        key_epoch: input.key_epoch,
+        items: [(0, [0; HASH_SIZE + SIG_SIZE]); HASH_CHAN_BATCH_SIZE],
+    };
+    for (item_in, item_out) in input.items.iter().zip(out.items.iter_mut()) {
+        if rpc_info.is_stale(input.seq, input.height) {
+            return None;
+        }
+        item_out.0 = item_in.0;
+        item_out.1[..HASH_SIZE].copy_from_slice(&item_in.1);
+        let sig = rpc_info.miner_key.sign(&item_in.1);
+        item_out.1[HASH_SIZE..].copy_from_slice(&sig);
+    }
+    Some(out)
+}
+
+/// Signs `input` and sends it on, returning false once stopping.
+pub(super) fn sign_and_send(
+    rpc_info: &RpcInfo,
+    input: &PartialHashBatch<[u8; HASH_SIZE]>,
+    outputs: &Sender<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+) -> bool {
+    match sign(rpc_info, input) {
+        Some(out) => select! {
+            send(outputs, out) -> res => res.is_ok(),
+            recv(rpc_info.stop) -> _ => false,
+        },
+        None => {
+            // Its first hashes went to waste
+            rpc_info
+                .stale_hashes
+                .fetch_add(HASH_CHAN_BATCH_SIZE, atomic::Ordering::Relaxed);
+            true
+        }
+    }
+}
+
+/// Returns once stopping, or once `assignment` says to run RandomX instead.
+pub(super) fn run(
     rpc_info: Arc<RpcInfo>,
     inputs: Receiver<PartialHashBatch<[u8; HASH_SIZE]>>,
     outputs: Sender<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
+    assignment: Option<&Assignment>,
 ) {
-    for input in inputs {
-        let mut out = PartialHashBatch {
-            seq: input.seq,
-            height: input.height,
-            items: [(0, [0; HASH_SIZE + SIG_SIZE]); HASH_CHAN_BATCH_SIZE],
+    let unassigned = never();
+    let moved = assignment.map_or(&unassigned, Assignment::moved);
+    loop {
+        // The supervisor keeps the channels open, so only `stop` says to
+        let input = select! {
+            recv(inputs) -> input => match input {
+                Ok(input) => Some(input),
+                Err(_) => return,
+            },
+            recv(moved) -> _ => None,
+            recv(rpc_info.stop) -> _ => return,
         };
-        for (item_in, item_out) in input.items.iter().zip(out.items.iter_mut()) {
-            item_out.0 = item_in.0;
-            item_out.1[..HASH_SIZE].copy_from_slice(&item_in.1);
-            let sig = rpc_info.miner_key.sign(&item_in.1);
-            item_out.1[HASH_SIZE..].copy_from_slice(&sig);
+        if let Some(input) = input {
+            if !sign_and_send(&rpc_info, &input, &outputs) {
+                return;
+            }
         }
-        if outputs.send(out).is_err() {
+        if matches!(assignment, Some(a) if !a.signing()) {
             return;
         }
     }
 }
 
+/// Starts the `index`th signer.
 pub fn start(
+    supervisor: &mut Supervisor,
     rpc_info: Arc<RpcInfo>,
+    index: usize,
     inputs: Receiver<PartialHashBatch<[u8; HASH_SIZE]>>,
     outputs: Sender<PartialHashBatch<[u8; HASH_SIZE + SIG_SIZE]>>,
 ) {
-    std::thread::spawn(|| run(rpc_info, inputs, outputs));
+    supervisor.spawn("signer", move || {
+        rpc_info.placement.pin_signer(index);
+        run(rpc_info.clone(), inputs.clone(), outputs.clone(), None)
+    });
 }
@@ -0,0 +1,272 @@
+//! Keeping the worker threads going: noticing when one stops, and restarting it.
+//!
+//! A thread which keeps failing soon after being restarted gets longer and longer waits,
+//! until we give up on it, and on mining, as it'd only be limping along.
+
+use super::{spawn_tracked, ShutdownError};
+use crossbeam_channel::{
+    after, never, select, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError,
+};
+use log::{error, info, warn};
+use std::{
+    any::Any,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How long to wait before restarting a thread which stopped.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// The wait after the first failure, which doubles with each failure after.
+    pub first: Duration,
+    pub max: Duration,
+    /// A thread which ran for this long since it was last started has recovered,
+    /// and its next failure counts as the first again.
+    pub recovered_after: Duration,
+    /// How many times in a row a thread is restarted before we give up on it.
+    pub max_restarts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            first: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            recovered_after: Duration::from_secs(300),
+            max_restarts: 5,
+        }
+    }
+}
+
+impl Backoff {
+    fn wait(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.first
+            .checked_mul(1 << doublings)
+            .map_or(self.max, |wait| wait.min(self.max))
+    }
+}
+
+/// Tells the supervisor a thread stopped, however it stopped.
+struct Exited {
+    id: usize,
+    send: Sender<usize>,
+}
+
+impl Drop for Exited {
+    fn drop(&mut self) {
+        let _ = self.send.send(self.id);
+    }
+}
+
+struct Slot {
+    name: &'static str,
+    run: Arc<dyn Fn() + Send + Sync>,
+    handle: Option<JoinHandle<()>>,
+    started: Instant,
+    /// Failures since the thread last recovered.
+    failures: u32,
+    restart_at: Option<Instant>,
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause")
+}
+
+pub struct Supervisor {
+    backoff: Backoff,
+    slots: Vec<Slot>,
+    exits_send: Sender<usize>,
+    exits: Receiver<usize>,
+    /// Disconnected once we should stop restarting threads, and wait for them all to stop.
+    stop: Receiver<()>,
+    /// Dropped once we give up on a thread.
+    gave_up_send: Option<Sender<()>>,
+    gave_up: Receiver<()>,
+    gave_up_on: Option<&'static str>,
+    panicked_while_stopping: bool,
+}
+
+impl Supervisor {
+    pub fn new(backoff: Backoff, stop: Receiver<()>) -> Supervisor {
+        let (exits_send, exits) = unbounded();
+        let (gave_up_send, gave_up) = unbounded();
+        Supervisor {
+            backoff,
+            slots: Vec::new(),
+            exits_send,
+            exits,
+            stop,
+            gave_up_send: Some(gave_up_send),
+            gave_up,
+            gave_up_on: None,
+            panicked_while_stopping: false,
+        }
+    }
+
+    fn start_thread(&mut self, id: usize) {
+        let slot = &mut self.slots[id];
+        let run = slot.run.clone();
+        let exited = Exited {
+            id,
+            send: self.exits_send.clone(),
+        };
+        let handle = std::thread::Builder::new()
+            .name(slot.name.to_owned())
+            .spawn(move || {
+                let _exited = exited;
+                run()
+            })
+            .expect("Failed to spawn thread");
+        slot.handle = Some(handle);
+        slot.started = Instant::now();
+        slot.restart_at = None;
+    }
+
+    /// Starts a thread running `run`, and runs it again whenever it stops, until we're stopping.
+    /// Anything a run needs to hand off to the next, like channels, belongs in the closure.
+    pub fn spawn<F: Fn() + Send + Sync + 'static>(&mut self, name: &'static str, run: F) {
+        self.slots.push(Slot {
+            name,
+            run: Arc::new(run),
+            handle: None,
+            started: Instant::now(),
+            failures: 0,
+            restart_at: None,
+        });
+        self.start_thread(self.slots.len() - 1);
+    }
+
+    /// Returns whether the thread stopped because we're stopping, before we noticed ourselves.
+    fn exited(&mut self, id: usize, stopping: bool) -> bool {
+        let slot = &mut self.slots[id];
+        let res = match slot.handle.take() {
+            Some(handle) => handle.join(),
+            None => return false,
+        };
+        if stopping {
+            if let Err(payload) = res {
+                error!(
+                    "{} thread panicked while stopping: {}",
+                    slot.name,
+                    panic_message(&*payload),
+                );
+                self.panicked_while_stopping = true;
+            }
+            return false;
+        }
+        match res {
+            // It saw the stop first, which isn't a failure
+            Ok(()) if !matches!(self.stop.try_recv(), Err(TryRecvError::Empty)) => return true,
+            Ok(()) => warn!("{} thread stopped unexpectedly", slot.name),
+            Err(payload) => error!(
+                "{} thread panicked: {}",
+                slot.name,
+                panic_message(&*payload)
+            ),
+        }
+        if slot.started.elapsed() >= self.backoff.recovered_after {
+            slot.failures = 0;
+        }
+        slot.failures += 1;
+        if slot.failures > self.backoff.max_restarts {
+            error!(
+                "{} thread failed {} times in a row, giving up on it",
+                slot.name, slot.failures,
+            );
+            self.gave_up_on = Some(slot.name);
+            self.gave_up_send = None;
+            return false;
+        }
+        let wait = self.backoff.wait(slot.failures);
+        info!("restarting {} thread in {:?}", slot.name, wait);
+        slot.restart_at = Some(Instant::now() + wait);
+        false
+    }
+
+    fn stop_restarting(&mut self) {
+        for slot in &mut self.slots {
+            slot.restart_at = None;
+        }
+    }
+
+    fn run(mut self) -> Result<(), ShutdownError> {
+        let mut stopping = false;
+        loop {
+            if stopping && self.slots.iter().all(|s| s.handle.is_none()) {
+                break;
+            }
+            let next_restart = self.slots.iter().filter_map(|s| s.restart_at).min();
+            let restart = match next_restart {
+                Some(at) => after(at.saturating_duration_since(Instant::now())),
+                None => never(),
+            };
+            let stop = if stopping { never() } else { self.stop.clone() };
+            select! {
+                recv(self.exits) -> id => {
+                    // We hold a sender ourselves
+                    if self.exited(id.unwrap(), stopping) {
+                        stopping = true;
+                        self.stop_restarting();
+                    }
+                },
+                recv(stop) -> _ => {
+                    stopping = true;
+                    self.stop_restarting();
+                },
+                recv(restart) -> _ => {
+                    let now = Instant::now();
+                    let due: Vec<_> = (0..self.slots.len())
+                        .filter(|&id| matches!(self.slots[id].restart_at, Some(at) if at <= now))
+                        .collect();
+                    for id in due {
+                        self.start_thread(id);
+                    }
+                },
+            }
+        }
+        match self.gave_up_on {
+            Some(name) => Err(ShutdownError::GaveUp(name)),
+            None if self.panicked_while_stopping => Err(ShutdownError::Panicked),
+            None => Ok(()),
+        }
+    }
+
+    /// Supervises from a thread of its own, until `stop` and every thread has stopped.
+    pub fn start(self) -> Supervised {
+        let gave_up = self.gave_up.clone();
+        let (exited_send, exited) = unbounded();
+        let handle = spawn_tracked(exited_send, move || self.run());
+        Supervised {
+            handle,
+            exited,
+            gave_up,
+        }
+    }
+}
+
+pub struct Supervised {
+    handle: JoinHandle<Result<(), ShutdownError>>,
+    exited: Receiver<()>,
+    gave_up: Receiver<()>,
+}
+
+impl Supervised {
+    /// Disconnected once a thread kept failing and we gave up on it, so mining should stop.
+    pub fn gave_up(&self) -> &Receiver<()> {
+        &self.gave_up
+    }
+
+    /// Waits for the supervisor, and so every thread, to stop, unless that takes until `deadline`.
+    pub fn join(self, deadline: Instant) -> Result<(), ShutdownError> {
+        if let Err(RecvTimeoutError::Timeout) = self.exited.recv_deadline(deadline) {
+            return Err(ShutdownError::TimedOut);
+        }
+        self.handle.join().unwrap_or(Err(ShutdownError::Panicked))
+    }
+}
@@ -1,4 +1,5 @@
 use num::BigUint;
+use std::cmp::Ordering;
 
 pub fn difficulty_to_max_hash(mut difficulty: u64) -> [u8; 32] {
     if difficulty == 0 {
@@ -13,3 +14,18 @@ pub fn difficulty_to_max_hash(mut difficulty: u64) -> [u8; 32] {
     out[..bytes.len()].copy_from_slice(&bytes);
     out
 }
+
+/// Whether hash `a` is below `b`, both little endian, as a block's hash is checked against the max hash.
+/// The lowest byte is never compared.
+pub fn less_than_rev(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    let mut i = 31;
+    while i > 0 {
+        match a[i].cmp(&b[i]) {
+            Ordering::Less => return true,
+            Ordering::Greater => return false,
+            Ordering::Equal => {}
+        }
+        i -= 1;
+    }
+    false
+}
@@ -0,0 +1,58 @@
+//! Checking a block the way a node would, from its header, nonce and signature.
+//!
+//! The hashes are computed just as the RandomX threads compute them: the first of the header
+//! followed by the nonce, and the second of the first hash followed by its signature.
+
+use crate::{
+    bls::{PublicKey, SIG_SIZE},
+    utils::{difficulty_to_max_hash, less_than_rev},
+};
+use randomx::{Vm, HASH_SIZE};
+
+/// Every value along the way to a block's hash, and whether it checked out.
+pub struct Verification {
+    pub first_input: Vec<u8>,
+    pub first_hash: [u8; HASH_SIZE],
+    /// Whether the signature is the miner's, of the first hash. None without the miner's key.
+    pub signature_valid: Option<bool>,
+    pub second_input: Vec<u8>,
+    pub second_hash: [u8; HASH_SIZE],
+    /// Without a difficulty, None.
+    pub max_hash: Option<[u8; 32]>,
+    pub meets_difficulty: Option<bool>,
+}
+
+impl Verification {
+    /// Whether every check which could be made passed.
+    pub fn valid(&self) -> bool {
+        self.signature_valid != Some(false) && self.meets_difficulty != Some(false)
+    }
+}
+
+pub fn verify(
+    vm: &mut Vm,
+    header: &[u8],
+    nonce: u32,
+    signature: &[u8; SIG_SIZE],
+    miner_key: Option<&PublicKey>,
+    difficulty: Option<u64>,
+) -> Verification {
+    let mut first_input = header.to_vec();
+    first_input.extend(&nonce.to_le_bytes());
+    let first_hash = vm.hash(&first_input);
+    let signature_valid = miner_key.map(|key| key.verify(&first_hash, signature));
+    let mut second_input = first_hash.to_vec();
+    second_input.extend(signature as &[u8]);
+    let second_hash = vm.hash(&second_input);
+    let max_hash = difficulty.map(difficulty_to_max_hash);
+    let meets_difficulty = max_hash.map(|max_hash| less_than_rev(&second_hash, &max_hash));
+    Verification {
+        first_input,
+        first_hash,
+        signature_valid,
+        second_input,
+        second_hash,
+        max_hash,
+        meets_difficulty,
+    }
+}